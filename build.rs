@@ -0,0 +1,24 @@
+use sha2::{Digest, Sha256};
+use std::fs;
+
+// The EFF large wordlist (https://www.eff.org/dice) is embedded for passphrases and server key
+// fingerprint words, so a corrupted or substituted copy would change both. Refuse to build unless
+// it is exactly the published file.
+const WORDLIST: &str = "assets/eff_large_wordlist.txt";
+const WORDLIST_SHA256: &str = "addd35536511597a02fa0a9ff1e5284677b8883b83e986e43f15a3db996b903e";
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", WORDLIST);
+    let bytes =
+        fs::read(WORDLIST).unwrap_or_else(|err| panic!("Could not read {}: {}", WORDLIST, err));
+    let digest: String = Sha256::digest(&bytes)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    if digest != WORDLIST_SHA256 {
+        panic!(
+            "{} has SHA-256 {}, expected {}; restore it from git or eff.org",
+            WORDLIST, digest, WORDLIST_SHA256
+        );
+    }
+}
@@ -0,0 +1,87 @@
+#!/bin/sh
+# Runs keypost-cli processes in parallel against one throwaway profile, to check that the state
+# directory's locks keep their changes from interleaving and that waiters time out cleanly. Needs
+# no server, and flock(1) from util-linux. Cargo arguments (such as --features) are passed through.
+# `cargo test` runs it (tests/concurrency.rs) against the binary it built, given as KEYPOST_BIN.
+#   $ ./scripts/test_concurrency.sh
+cd "$(dirname "$0")/.."
+BIN="$KEYPOST_BIN"
+if [ -z "$BIN" ]; then
+    cargo build --quiet "$@" || exit 1
+    BIN="$(pwd)/target/debug/keypost-cli"
+fi
+DIR="$(mktemp -d)"
+trap 'rm -rf "$DIR"' EXIT
+chmod 700 "$DIR"
+FAILED=0
+
+keypost() {
+    "$BIN" --state-dir "$DIR" "$@"
+}
+
+check() {
+    if [ "$1" = 0 ]; then
+        echo "ok   $2"
+    else
+        echo "FAIL $2"
+        FAILED=1
+    fi
+}
+
+# Every process migrates, or waits for, the same profile as the baseline client left it (a session
+# and a pinned server key in loose files); the migration must happen once.
+{ head -c 20 /dev/urandom; printf 'foo@bar.com'; } | base64 -w 0 > "$DIR/session_id.public"
+printf 'AQIDBA==' > "$DIR/server.public"
+chmod 600 "$DIR"/*.public
+for i in $(seq 20); do
+    keypost server-key show > "$DIR/show.$i.out" 2>&1 &
+done
+wait
+grep -q '"pinned": "AQIDBA=="' "$DIR/state.json" && grep -q '"email": "foo@bar.com"' "$DIR/state.json" \
+    && ! ls "$DIR"/*.public > /dev/null 2>&1
+check $? "parallel commands migrate a baseline profile exactly once"
+! grep -L 'Pinned server key' "$DIR"/show.*.out | grep -q .
+check $? "every parallel reader sees the migrated state"
+
+# A locker read on a logged-in legacy profile must find the migrated session rather than report
+# that nobody is logged in. There is no server, so the read itself fails after that.
+LEGACY="$(mktemp -d)"
+chmod 700 "$LEGACY"
+printf '{ "server_url": "http://127.0.0.1:9", "http": { "max_retries": 0 } }' > "$LEGACY/config.json"
+{ head -c 20 /dev/urandom; printf 'foo@bar.com'; } | base64 -w 0 > "$LEGACY/session_id.public"
+head -c 64 /dev/urandom | base64 -w 0 > "$LEGACY/export_key.private"
+chmod 600 "$LEGACY"/*
+"$BIN" --state-dir "$LEGACY" get foo < /dev/null > "$LEGACY/get.out" 2>&1
+! grep -q 'No session available' "$LEGACY/get.out" && grep -q '"email": "foo@bar.com"' "$LEGACY/state.json"
+check $? "a locker read on a legacy profile uses its migrated session"
+rm -rf "$LEGACY"
+
+# Racing read-modify-writes must leave a valid document and no temporary files behind.
+for i in $(seq 20); do
+    keypost server-key trust --fingerprint "$(printf '%064x' "$i")" > /dev/null &
+    keypost server-key reset > /dev/null &
+    keypost status > /dev/null &
+done
+wait
+keypost server-key show > /dev/null && ! ls -A "$DIR" | grep -q '\.tmp$'
+check $? "racing changes leave a valid state document"
+
+# A held exclusive lock makes others wait, and give up after lock_timeout_secs.
+printf '{ "lock_timeout_secs": 1 }' > "$DIR/config.json"
+chmod 600 "$DIR/config.json"
+flock -x "$DIR/.lock" sleep 3 &
+sleep .5
+! keypost status 2> "$DIR/timeout.err" > /dev/null && grep -q 'Timed out after 1s' "$DIR/timeout.err"
+check $? "a reader times out while another process holds the exclusive lock"
+wait
+
+# Readers don't wait for each other, but writers wait for readers.
+flock -s "$DIR/.lock" sleep 3 &
+sleep .5
+keypost status > /dev/null
+check $? "a reader proceeds while another process holds a shared lock"
+! keypost server-key reset 2> "$DIR/timeout.err" > /dev/null && grep -q 'Timed out' "$DIR/timeout.err"
+check $? "a writer times out while another process holds a shared lock"
+wait
+
+exit $FAILED
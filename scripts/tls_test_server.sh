@@ -0,0 +1,28 @@
+#!/bin/sh
+# A local TLS stand-in for keypost-app, for testing the client's TLS settings. Generates a
+# throwaway CA and a certificate for localhost, prints the matching config.json "tls" block and
+# serves HTTPS on the given port (default 8443) until interrupted.
+#   $ ./scripts/tls_test_server.sh 8443
+# Then set "server_url": "https://localhost:8443" in the test profile's config.json.
+set -e
+
+PORT="${1:-8443}"
+DIR="$(mktemp -d)"
+trap 'rm -rf "$DIR"' EXIT
+
+openssl req -x509 -newkey rsa:2048 -nodes -days 1 -subj "/CN=keypost test CA" \
+    -addext "basicConstraints=critical,CA:TRUE" -addext "keyUsage=critical,keyCertSign" \
+    -keyout "$DIR/ca.key" -out "$DIR/ca.pem" 2>/dev/null
+openssl req -newkey rsa:2048 -nodes -subj "/CN=localhost" \
+    -keyout "$DIR/server.key" -out "$DIR/server.csr" 2>/dev/null
+printf "subjectAltName=DNS:localhost,IP:127.0.0.1\nbasicConstraints=CA:FALSE\nextendedKeyUsage=serverAuth\n" > "$DIR/ext.cnf"
+openssl x509 -req -in "$DIR/server.csr" -CA "$DIR/ca.pem" -CAkey "$DIR/ca.key" -CAcreateserial \
+    -days 1 -extfile "$DIR/ext.cnf" -out "$DIR/server.pem" 2>/dev/null
+
+CA_BUNDLE="$(pwd)/keypost-test-ca.pem"
+cp "$DIR/ca.pem" "$CA_BUNDLE"
+PIN="$(openssl x509 -in "$DIR/server.pem" -pubkey -noout \
+    | openssl pkey -pubin -outform der | openssl dgst -sha256 -binary | base64)"
+
+echo "\"tls\": { \"ca_bundle\": \"$CA_BUNDLE\", \"spki_pins\": [\"sha256/$PIN\"] }"
+openssl s_server -quiet -www -accept "$PORT" -cert "$DIR/server.pem" -key "$DIR/server.key"
@@ -1,19 +1,52 @@
 use sha2::Digest;
 use sha2::Sha256;
 
-use crate::crypto;
+use std::io::ErrorKind;
+
+use crate::config;
+use crate::crypto::{self, KeypostKsf};
+use crate::hibp;
 use crate::http;
+use crate::locker;
 use crate::models::*;
+use crate::strength;
 use crate::util;
 
+// Export key from before a KSF migration, kept until every locker has been moved off it.
+const PREMIGRATION_EXPORT_KEY_FILE: &str = "export_key.premigration.private";
+
 pub fn login(client_email: String, client_password: String) -> Result<(), String> {
-    let (session_key, export_key) = execute_login_exchange(&client_email, &client_password)?;
+    let privacy = config::load()?.privacy;
+    let recorded_suite = check_cipher_suite()?;
+    let credential_id = privacy.credential_id(&client_email)?;
+    let (session_key, export_key, suite, session) = execute_login_exchange(
+        &client_email,
+        &credential_id,
+        privacy.enabled,
+        &client_password,
+    )
+    .map_err(|err| match recorded_suite {
+        Some(_) => err,
+        None => format!(
+            "{} (if this account was registered with a client built for a different cipher \
+             suite than {}, rebuild with that suite)",
+            err,
+            crypto::CIPHER_SUITE
+        ),
+    })?;
     // store the session and export keys (https://github.com/novifinancial/opaque-ke/blob/94fd3598d0bb8ae5747264112937e988f741ccbb/src/lib.rs#L620-L641)
-    util::write_to_secure_file("export_key.private", &export_key, true)
-        .map_err(|err| format!("Could not write export_key to file: {:?}", err))?;
-    util::write_to_secure_file("session_key.private", &session_key, true)
-        .map_err(|err| format!("Could not write session_key to file: {:?}", err))?;
-    Ok(())
+    // together with the session, under one exclusive lock, so that a login or logout in another
+    // process can't leave the profile with one login's keys and another's session.
+    util::lock::exclusive(|| {
+        util::write_to_secure_file("export_key.private", &export_key, true)?;
+        util::write_to_secure_file("session_key.private", &session_key, true)?;
+        util::state::update(|state| {
+            state.session = Some(session);
+            state.cipher_suite = Some(crypto::CIPHER_SUITE.to_string());
+            state.ksf_suite = Some(suite);
+        })
+    })
+    .map_err(|err| format!("Could not save the login to the profile: {}", err))
 }
 
 pub fn logout(session_id: &str) -> Result<String, String> {
@@ -22,28 +55,228 @@ pub fn logout(session_id: &str) -> Result<String, String> {
     Ok(response.o)
 }
 
+pub fn sessions(session_id: &str) -> Result<Vec<ServerSession>, String> {
+    match http::sessions(session_id) {
+        Ok(response) => Ok(response.sessions),
+        Err(CliError::UnauthorizedError) => Err("unauthorized".to_string()),
+        Err(err) => Err(format!("Could not list sessions: {}", err)),
+    }
+}
+
+// Revoke one of the account's sessions by its handle, or all of them. Revoking the current session
+// (or all of them) also removes it from this profile.
+pub fn revoke_session(
+    session_id: &str,
+    handle: Option<&str>,
+    current: bool,
+) -> Result<String, String> {
+    let response = match (handle, current) {
+        (Some(_), true) => http::logout(session_id),
+        _ => http::revoke_session(handle, session_id),
+    };
+    let response = match response {
+        Ok(response) => response,
+        Err(CliError::UnauthorizedError) => return Err("unauthorized".to_string()),
+        Err(err) => return Err(format!("Could not revoke session: {}", err)),
+    };
+    if current || handle.is_none() {
+        util::delete_session_file(session_id)
+            .map_err(|err| format!("Could not delete session file: {:?}", err))?;
+    }
+    Ok(response.o)
+}
+
 pub fn registration(
     _registration_key: String,
     client_email: String,
     client_password: String,
 ) -> Result<String, String> {
     // TODO registration_key provided by server once user has paid account.
-    let server_response = execute_registration_exchange(client_email, client_password)
-        .map_err(|err| format!("account registration error: {:?}", err))?;
+    let config = config::load()?;
+    strength::check(&client_password, &[&client_email], &config.password_policy)?;
+    if let Some(hibp_file) = &config.hibp_file {
+        if let Some(count) = hibp::open(hibp_file)?.lookup(&client_password)? {
+            return Err(format!(
+                "This password has appeared in {} known data breaches, please choose another.",
+                count
+            ));
+        }
+    }
+    let credential_id = config.privacy.credential_id(&client_email)?;
+    let ksf = config.ksf.build()?;
+    let server_response = execute_registration_exchange(
+        credential_id,
+        config.privacy.enabled,
+        client_password,
+        &ksf,
+        None,
+    )
+    .map_err(|err| format!("account registration error: {:?}", err))?;
+    record_cipher_suite()?;
     let response = server_response.o;
     Ok(response)
 }
 
+// Refuse to log in with a client built for a different cipher suite than the profile's account,
+// which would otherwise only fail with an opaque InvalidLoginError. Returns the recorded suite, if
+// the profile has one (profiles from before suites were recorded don't).
+fn check_cipher_suite() -> Result<Option<String>, String> {
+    let recorded = util::state::load()
+        .map_err(|err| format!("Could not read profile state: {}", err))?
+        .cipher_suite;
+    match recorded {
+        Some(recorded) if recorded != crypto::CIPHER_SUITE => Err(format!(
+            "This profile's account was registered with the {} cipher suite, but \
+             keypost-cli was built with {}. Rebuild with the matching suite{}.",
+            recorded,
+            crypto::CIPHER_SUITE,
+            match recorded.as_str() {
+                "ristretto255" => " (--features ristretto255)",
+                _ => " (without --features ristretto255)",
+            }
+        )),
+        recorded => Ok(recorded),
+    }
+}
+
+fn record_cipher_suite() -> Result<(), String> {
+    util::state::update(|state| state.cipher_suite = Some(crypto::CIPHER_SUITE.to_string()))
+        .map_err(|err| format!("Could not write profile state: {}", err))
+}
+
+// The key stretching suite the server announced at the last login.
+fn record_ksf_suite(suite: &str) -> Result<(), String> {
+    util::state::update(|state| state.ksf_suite = Some(suite.to_string()))
+        .map_err(|err| format!("Could not write profile state: {}", err))
+}
+
+// Each locker's name and whether it was moved to the new export key.
+type MigrationResults = Vec<(String, Result<(), String>)>;
+
+// Move the account to the profile's configured key stretching suite. Changing the KSF changes the
+// export key, so the account is re-registered and then every locker is moved from the old export
+// key to the new one. The old key is kept on disk until all lockers have moved, and running the
+// migration again resumes an interrupted one.
+pub fn migrate_ksf(
+    client_email: String,
+    client_password: String,
+) -> Result<MigrationResults, String> {
+    let config = config::load()?;
+    let ksf = config.ksf.build()?;
+    login(client_email.clone(), client_password.clone())?;
+    let (session_id, _) = util::read_session_file()
+        .map_err(|err| format!("Could not read session file: {:?}", err))?;
+    let export_key = util::read_file("export_key.private", true)
+        .map_err(|err| format!("Could not read export_key: {:?}", err))?;
+    let premigration_key = match util::read_file(PREMIGRATION_EXPORT_KEY_FILE, true) {
+        Ok(key) => Some(key),
+        Err(err) if err.kind() == ErrorKind::NotFound => None,
+        Err(err) => {
+            return Err(format!(
+                "Could not read {}: {:?}",
+                PREMIGRATION_EXPORT_KEY_FILE, err
+            ))
+        }
+    };
+    // The suite the server announced at the login above, i.e. the one the account is registered
+    // with now.
+    let current_suite = util::state::load()
+        .map_err(|err| format!("Could not read profile state: {}", err))?
+        .ksf_suite
+        .unwrap_or_else(|| crypto::ksf::IDENTITY_SUITE.to_string());
+
+    match (premigration_key, current_suite == ksf.suite()) {
+        // Resuming: the account has already been re-registered.
+        (Some(_), true) => (),
+        (None, true) => return Err(format!("The account already uses {}", current_suite)),
+        // Not re-registered yet, or a previous run stopped before the server accepted it. Either
+        // way the login above used the old suite, so the current export key is the old one.
+        (premigration_key, false) => {
+            match premigration_key {
+                Some(key) if key != export_key => {
+                    return Err(format!(
+                        "{} does not match the account's current export key, refusing to \
+                         continue the migration",
+                        PREMIGRATION_EXPORT_KEY_FILE
+                    ))
+                }
+                Some(_) => (),
+                None => util::write_to_secure_file(PREMIGRATION_EXPORT_KEY_FILE, &export_key, true)
+                    .map_err(|err| {
+                        format!(
+                            "Could not write {}: {:?}",
+                            PREMIGRATION_EXPORT_KEY_FILE, err
+                        )
+                    })?,
+            }
+            execute_registration_exchange(
+                config.privacy.credential_id(&client_email)?,
+                config.privacy.enabled,
+                client_password,
+                &ksf,
+                Some(&session_id),
+            )
+            .map_err(|err| format!("account re-registration error: {:?}", err))?;
+            record_ksf_suite(&ksf.suite())?;
+        }
+    }
+
+    let old_key = util::read_file(PREMIGRATION_EXPORT_KEY_FILE, true)
+        .map_err(|err| format!("Could not read {}: {:?}", PREMIGRATION_EXPORT_KEY_FILE, err))?;
+    let new_key = util::read_file("export_key.private", true)
+        .map_err(|err| format!("Could not read export_key: {:?}", err))?;
+    let index = util::read_locker_index()
+        .map_err(|err| format!("Could not read locker index: {:?}", err))?;
+    let results: Vec<(String, Result<(), String>)> = index
+        .iter()
+        .map(|entry| {
+            let result = locker::move_locker(
+                &entry.id,
+                &client_email,
+                &old_key,
+                &new_key,
+                &entry.tags,
+                &session_id,
+            );
+            (entry.id.to_string(), result)
+        })
+        .collect();
+    if results.iter().all(|(_, result)| result.is_ok()) {
+        util::delete_file(PREMIGRATION_EXPORT_KEY_FILE).map_err(|err| {
+            format!(
+                "Could not delete {}: {:?}",
+                PREMIGRATION_EXPORT_KEY_FILE, err
+            )
+        })?;
+    }
+    Ok(results)
+}
+
 // https://docs.rs/opaque-ke/0.6.0/opaque_ke/#structs
 // https://docs.rs/opaque-ke/0.6.0/opaque_ke/struct.ServerRegistrationStartResult.html
 // https://docs.rs/opaque-ke/0.6.0/opaque_ke/struct.ServerRegistration.html
+// In privacy mode the credential id is a blinded identifier rather than the email, and it is bound
+// into the OPAQUE envelope as the client identity. With `upgrade_auth` set, an existing, logged in
+// account is re-registered in place.
 fn execute_registration_exchange(
-    client_email: String,
+    credential_id: String,
+    bind_identifier: bool,
     client_password: String,
+    ksf: &KeypostKsf,
+    upgrade_auth: Option<&str>,
 ) -> Result<RegisterResponse, String> {
+    let (start_path, finish_path) = match upgrade_auth {
+        Some(_) => ("/register/upgrade/start", "/register/upgrade/finish"),
+        None => ("/register/start", "/register/finish"),
+    };
+    let client_identifier = match bind_identifier {
+        true => Some(credential_id.as_bytes()),
+        false => None,
+    };
     let mut client_rng = crypto::opaque::rng();
     let client_registration_start_result =
-        crypto::opaque::register_start(&mut client_rng, client_password.clone());
+        crypto::opaque::register_start(&mut client_rng, client_password.clone())
+            .map_err(|err| format!("account register start error: {}", err))?;
     let registration_request_bytes = client_registration_start_result.message.serialize();
 
     let pkce_code_verify = pkce::code_verifier(128);
@@ -51,10 +284,12 @@ fn execute_registration_exchange(
     let pkce_code_challenge = pkce::code_challenge(&pkce_code_verify);
 
     let server_response: RegisterResponse = http::register_start(
-        "http://localhost:8000/register/start",
-        &client_email,
+        start_path,
+        &credential_id,
         &base64::encode(registration_request_bytes),
         &pkce_code_challenge,
+        &ksf.suite(),
+        upgrade_auth,
     )
     .map_err(|_e| String::from("Error getting response from register/start"))?;
 
@@ -63,85 +298,146 @@ fn execute_registration_exchange(
         client_password,
         client_registration_start_result,
         &server_response.o,
+        client_identifier,
+        ksf,
     )
-    .map_err(|err| format!("account register finish error: {:?}", err))?;
-    util::write_to_secure_file("export_key.private", &client_export_key, true)
-        .map_err(|_| "Could not write export_key to file!")?;
+    .map_err(|err| format!("account register finish error: {}", err))?;
 
     let server_response: RegisterResponse = http::register_finish(
-        "http://localhost:8000/register/finish",
+        finish_path,
         server_response.id,
-        &client_email,
+        &credential_id,
         &base64::encode(client_message_bytes),
         &pkce_code_verify_b64,
+        upgrade_auth,
     )
     .map_err(|err| format!("http register finish error: {:?}", err))?;
+    // Only replace the export key once the server has accepted the registration, so that a failed
+    // re-registration leaves the profile with the key its lockers are stored under.
+    if server_response.o != "Success" {
+        return Err(format!("register finish error: {}", server_response.o));
+    }
+    util::write_to_secure_file("export_key.private", &client_export_key, true)
+        .map_err(|_| "Could not write export_key to file!")?;
     Ok(server_response)
 }
 
 fn execute_login_exchange(
     client_email: &str,
+    credential_id: &str,
+    bind_identifier: bool,
     client_password: &str,
-) -> Result<(Vec<u8>, Vec<u8>), String> {
+) -> Result<(Vec<u8>, Vec<u8>, String, util::state::Session), String> {
+    let client_identifier = match bind_identifier {
+        true => Some(credential_id.as_bytes()),
+        false => None,
+    };
     let client_login_start_result = crypto::opaque::login_start(client_password)
-        .map_err(|err| format!("account login start error: {:?}", err))?;
+        .map_err(|err| format!("account login start error: {}", err))?;
     let credential_request_bytes = client_login_start_result.message.serialize();
 
     // Client sends credential_request_bytes to server
     let credential_response =
-        http::login_start(client_email, &base64::encode(credential_request_bytes))
+        http::login_start(credential_id, &base64::encode(credential_request_bytes))
             .map_err(|err| format!("Failed login_start: {:?}", err))?;
-    let credential_response_bytes =
-        base64::decode(&credential_response.o).map_err(|_| "Could not decode base64 str")?;
+    let credential_response_bytes = base64::decode(&credential_response.o)
+        .map_err(|err| CliError::MalformedResponseError(err.to_string()).to_string())?;
+    let ksf = KeypostKsf::from_suite(&credential_response.s).map_err(|err| err.to_string())?;
 
     let (credential_finalization_bytes, client_session_key, client_export_key) =
         crypto::opaque::login_finish(
             client_password.to_string(),
             client_login_start_result,
             &credential_response_bytes,
+            client_identifier,
+            &ksf,
         )
-        .map_err(|err| format!("account login finish error: {:?}", err))?;
+        .map_err(|err| format!("account login finish error: {}", err))?;
     let credential_finalization_str = base64::encode(credential_finalization_bytes);
 
     // Client sends credential_finalization_bytes to server
     let login_response = http::login_finish(
         credential_response.id,
-        client_email,
+        credential_id,
         &credential_finalization_str,
     )
     .map_err(|err| format!("Could not get a LoginResponse: {:?}", err))?;
 
     match execute_login_verify(login_response, &client_session_key, client_email) {
-        Ok(()) => Ok((client_session_key, client_export_key)),
+        Ok(session) => Ok((client_session_key, client_export_key, ksf.suite(), session)),
         Err(err) => Err(err),
     }
 }
 
+// The server picks the secure channel version in its login response. Version 2 proves knowledge
+// of the session key over a SecureChannel and derives the session id from it, both bound to the
+// offered and chosen versions; version 1 servers only get the legacy repeated-u32 nonce scheme if
+// the profile sets allow_legacy_channel.
 fn execute_login_verify(
     response: LoginResponse,
     client_session_key: &[u8],
     email: &str,
-) -> Result<(), String> {
+) -> Result<util::state::Session, String> {
     match response.o.as_str() {
         "Failed" => Err("login_finish error".to_string()),
         rand_challenge => {
             let rand_bytes = base64::decode(rand_challenge)
-                .map_err(|_| "Could not base64 decode rand_challenge")?;
-            let nonce = crypto::expand_u32_nonce(&response.id);
-            let ciphertext = &crypto::encrypt_bytes(&nonce, client_session_key, &rand_bytes);
-            let hash_bytes = Sha256::digest(ciphertext);
+                .map_err(|err| CliError::MalformedResponseError(err.to_string()).to_string())?;
+            let context = crypto::channel::login_context(
+                response.id,
+                crypto::channel::CHANNEL_VERSION,
+                response.v,
+            );
+            let allow_legacy = config::load()?.allow_legacy_channel;
+            let (ciphertext, session_id) =
+                match response.v {
+                    crypto::channel::CHANNEL_VERSION => (
+                        crypto::SecureChannel::client(client_session_key, &context)
+                            .seal(&rand_bytes)
+                            .map_err(|err| err.to_string())?,
+                        crypto::channel::derive_session_id(client_session_key, &context).to_vec(),
+                    ),
+                    // The legacy scheme can't authenticate the version choice, so a server (or a
+                    // man-in-the-middle) choosing it is refused unless the profile allows it.
+                    crypto::channel::LEGACY_CHANNEL_VERSION if !allow_legacy => return Err(
+                        "Server chose the legacy secure channel (version 1), which this profile \
+                         does not allow. Set allow_legacy_channel in config.json only if the \
+                         server is known to be too old for version 2."
+                            .to_string(),
+                    ),
+                    crypto::channel::LEGACY_CHANNEL_VERSION => (
+                        crypto::encrypt_bytes(
+                            &crypto::expand_u32_nonce(&response.id),
+                            client_session_key,
+                            &rand_bytes,
+                        )
+                        .map_err(|err| err.to_string())?,
+                        crypto::encrypt_bytes_with_u32_nonce(
+                            &response.id,
+                            client_session_key,
+                            &response.id.to_be_bytes(),
+                        )
+                        .map_err(|err| err.to_string())?,
+                    ),
+                    version => {
+                        return Err(format!(
+                            "Server chose unsupported secure channel version {}",
+                            version
+                        ))
+                    }
+                };
+            let hash_bytes = Sha256::digest(&ciphertext);
             let hash = base64::encode(hash_bytes);
             let server_response = http::login_verify(response.id, &hash)
                 .map_err(|err| format!("Error during login_verify request: {:?}", err))?;
-            let session_id = crypto::encrypt_bytes_with_u32_nonce(
-                &response.id,
-                client_session_key,
-                &[response.id.to_be_bytes()].concat(),
-            );
-            util::write_session_file(&session_id, email)
-                .map_err(|err| format!("Could not write session_id to file: {:?}", err))?;
             match server_response.o.as_str() {
-                "Success" => Ok(()),
+                "Success" => Ok(util::state::Session {
+                    id: base64::encode(&session_id),
+                    email: email.to_string(),
+                    channel_version: response.v,
+                    logged_in_at: Some(util::now()),
+                    expires_at: server_response.x,
+                }),
                 _ => Err("login_verify error".to_string()),
             }
         }
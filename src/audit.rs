@@ -0,0 +1,319 @@
+use hmac::{Hmac, Mac, NewMac};
+use serde::Serialize;
+use sha2::Sha256;
+use std::collections::HashMap;
+use zeroize::Zeroizing;
+
+use crate::config::AuditConfig;
+use crate::crypto;
+use crate::hibp::BreachIndex;
+use crate::locker;
+use crate::models::Secret;
+use crate::strength;
+use crate::util::{self, LockerIndexEntry};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+pub struct BreachReport {
+    pub breached: Vec<BreachFinding>,
+    pub unreadable: Vec<UnreadableFinding>,
+}
+
+pub struct BreachFinding {
+    pub locker_id: String,
+    pub count: u32,
+}
+
+#[derive(Serialize, Debug)]
+pub struct HealthReport {
+    pub lockers: usize,
+    pub reused: Vec<Vec<String>>,
+    pub weak: Vec<WeakFinding>,
+    pub stale: Vec<StaleFinding>,
+    pub unreadable: Vec<UnreadableFinding>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct WeakFinding {
+    pub locker_id: String,
+    pub entropy_bits: f64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct StaleFinding {
+    pub locker_id: String,
+    pub age_days: u64,
+}
+
+// A locker that could not be opened or parsed (e.g. a legacy ciphertext that needs a rewrap). It
+// is reported and the audit carries on with the rest.
+#[derive(Serialize, Debug)]
+pub struct UnreadableFinding {
+    pub locker_id: String,
+    pub error: String,
+}
+
+// Open every locker and look its secret up in the local breach dataset. Secrets are only ever
+// hashed locally; nothing about them leaves the machine.
+pub fn breaches(
+    index: &mut BreachIndex,
+    locker_ids: &[String],
+    email: &str,
+    export_key: &[u8],
+    session_id: &str,
+) -> Result<BreachReport, String> {
+    let mut report = BreachReport {
+        breached: Vec::new(),
+        unreadable: Vec::new(),
+    };
+    for locker_id in locker_ids {
+        let secret = match open_secret(locker_id, email, export_key, session_id) {
+            Ok(secret) => secret,
+            Err(error) => {
+                report.unreadable.push(unreadable(locker_id, error)?);
+                continue;
+            }
+        };
+        if let Some(count) = index.lookup(&secret)? {
+            report.breached.push(BreachFinding {
+                locker_id: locker_id.to_string(),
+                count,
+            });
+        }
+    }
+    Ok(report)
+}
+
+// Open every locker and report reused, weak and stale secrets. Reuse is detected by comparing
+// HMACs under a key that only exists for the duration of the audit, so plaintexts are never
+// compared, stored or printed.
+pub fn health(
+    lockers: &[LockerIndexEntry],
+    email: &str,
+    export_key: &[u8],
+    session_id: &str,
+    config: &AuditConfig,
+) -> Result<HealthReport, String> {
+    classify(
+        lockers,
+        |locker_id| open_secret(locker_id, email, export_key, session_id),
+        email,
+        config,
+        util::now(),
+    )
+}
+
+fn classify(
+    lockers: &[LockerIndexEntry],
+    mut open: impl FnMut(&str) -> Result<Zeroizing<String>, String>,
+    email: &str,
+    config: &AuditConfig,
+    now: u64,
+) -> Result<HealthReport, String> {
+    let audit_key = Zeroizing::new(crypto::rand_bytes());
+    let mut by_hash: HashMap<Vec<u8>, Vec<String>> = HashMap::new();
+    let mut weak = Vec::new();
+    let mut stale = Vec::new();
+    let mut unreadable_lockers = Vec::new();
+    for entry in lockers {
+        let secret = match open(&entry.id) {
+            Ok(secret) => secret,
+            Err(error) => {
+                unreadable_lockers.push(unreadable(&entry.id, error)?);
+                continue;
+            }
+        };
+        let mut mac = Hmac::<Sha256>::new_from_slice(&audit_key)
+            .map_err(|_| "Could not create audit HMAC".to_string())?;
+        mac.update(secret.as_bytes());
+        by_hash
+            .entry(mac.finalize().into_bytes().to_vec())
+            .or_default()
+            .push(entry.id.to_string());
+
+        let entropy_bits = strength::estimate(&secret, &[email]).entropy_bits;
+        if entropy_bits < config.min_entropy_bits {
+            weak.push(WeakFinding {
+                locker_id: entry.id.to_string(),
+                entropy_bits,
+            });
+        }
+        let age_days = now.saturating_sub(entry.updated_at) / SECONDS_PER_DAY;
+        if age_days > config.max_age_days {
+            stale.push(StaleFinding {
+                locker_id: entry.id.to_string(),
+                age_days,
+            });
+        }
+    }
+    let mut reused: Vec<Vec<String>> = by_hash.into_values().filter(|ids| ids.len() > 1).collect();
+    reused.sort();
+    Ok(HealthReport {
+        lockers: lockers.len(),
+        reused,
+        weak,
+        stale,
+        unreadable: unreadable_lockers,
+    })
+}
+
+// An expired session is not a problem with the locker: it ends the audit so that the caller can
+// log in again and rerun it.
+fn unreadable(locker_id: &str, error: String) -> Result<UnreadableFinding, String> {
+    match error.as_str() {
+        "unauthorized" | "no_session" => Err(error),
+        _ => Ok(UnreadableFinding {
+            locker_id: locker_id.to_string(),
+            error,
+        }),
+    }
+}
+
+// The value of the locker's primary field (e.g. the password of a login).
+fn open_secret(
+    locker_id: &str,
+    email: &str,
+    export_key: &[u8],
+    session_id: &str,
+) -> Result<Zeroizing<String>, String> {
+    let secret = Secret::from_plaintext(locker::open_locker(
+        locker_id, email, export_key, session_id,
+    )?)?;
+    Ok(Zeroizing::new(
+        secret.primary_value().unwrap_or_default().to_string(),
+    ))
+}
+
+impl HealthReport {
+    pub fn is_healthy(&self) -> bool {
+        self.reused.is_empty()
+            && self.weak.is_empty()
+            && self.stale.is_empty()
+            && self.unreadable.is_empty()
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut lines = vec![format!("Audited {} lockers.", self.lockers)];
+        if self.is_healthy() {
+            lines.push("No reused, weak or stale secrets found.".to_string());
+        }
+        for ids in &self.reused {
+            lines.push(format!(
+                "Reused: the same secret is stored in {}",
+                ids.join(", ")
+            ));
+        }
+        for finding in &self.weak {
+            lines.push(format!(
+                "Weak: {} (~{:.0} bits of entropy)",
+                finding.locker_id, finding.entropy_bits
+            ));
+        }
+        for finding in &self.stale {
+            lines.push(format!(
+                "Stale: {} (last changed {} days ago)",
+                finding.locker_id, finding.age_days
+            ));
+        }
+        for finding in &self.unreadable {
+            lines.push(format!(
+                "Unreadable: {} ({})",
+                finding.locker_id, finding.error
+            ));
+        }
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_000 * SECONDS_PER_DAY;
+    const STRONG: &str = "vT7#qLz9!mWx2@Rk";
+
+    fn entry(id: &str, age_days: u64) -> LockerIndexEntry {
+        LockerIndexEntry {
+            id: id.to_string(),
+            updated_at: NOW - age_days * SECONDS_PER_DAY,
+            tags: Vec::new(),
+            blinded: true,
+        }
+    }
+
+    fn audit(lockers: &[(&str, Result<&str, &str>, u64)]) -> Result<HealthReport, String> {
+        let entries: Vec<_> = lockers.iter().map(|(id, _, age)| entry(id, *age)).collect();
+        let open = |locker_id: &str| {
+            let (_, secret, _) = lockers.iter().find(|(id, _, _)| *id == locker_id).unwrap();
+            secret
+                .map(|secret| Zeroizing::new(secret.to_string()))
+                .map_err(str::to_string)
+        };
+        classify(&entries, open, "foo@bar.com", &AuditConfig::default(), NOW)
+    }
+
+    #[test]
+    fn reports_reused_secrets_by_locker() {
+        let report = audit(&[
+            ("a", Ok(STRONG), 1),
+            ("b", Ok("Qp4$hN8&zKe1^Lw6"), 1),
+            ("c", Ok(STRONG), 1),
+        ])
+        .unwrap();
+        assert_eq!(report.reused, vec![vec!["a".to_string(), "c".to_string()]]);
+        assert!(report.weak.is_empty() && report.stale.is_empty());
+        assert!(!report.is_healthy());
+    }
+
+    #[test]
+    fn reports_weak_secrets() {
+        let report = audit(&[("weak", Ok("password1"), 1), ("strong", Ok(STRONG), 1)]).unwrap();
+        let weak: Vec<_> = report.weak.iter().map(|f| f.locker_id.as_str()).collect();
+        assert_eq!(weak, vec!["weak"]);
+        assert!(report.weak[0].entropy_bits < AuditConfig::default().min_entropy_bits);
+    }
+
+    #[test]
+    fn reports_secrets_older_than_the_maximum_age() {
+        let report = audit(&[
+            ("old", Ok(STRONG), 366),
+            ("new", Ok("Qp4$hN8&zKe1^Lw6"), 365),
+        ])
+        .unwrap();
+        assert_eq!(report.stale.len(), 1);
+        assert_eq!(report.stale[0].locker_id, "old");
+        assert_eq!(report.stale[0].age_days, 366);
+    }
+
+    #[test]
+    fn unreadable_lockers_are_reported_and_skipped() {
+        let report = audit(&[
+            ("legacy", Err("legacy ciphertext"), 1),
+            ("fine", Ok(STRONG), 1),
+        ])
+        .unwrap();
+        assert_eq!(report.lockers, 2);
+        assert_eq!(report.unreadable.len(), 1);
+        assert_eq!(report.unreadable[0].locker_id, "legacy");
+        assert_eq!(report.unreadable[0].error, "legacy ciphertext");
+        assert!(report.reused.is_empty() && report.weak.is_empty());
+        assert!(!report.is_healthy());
+    }
+
+    #[test]
+    fn an_expired_session_ends_the_audit() {
+        assert_eq!(
+            audit(&[("a", Ok(STRONG), 1), ("b", Err("unauthorized"), 1)]).unwrap_err(),
+            "unauthorized"
+        );
+    }
+
+    #[test]
+    fn a_vault_of_distinct_strong_fresh_secrets_is_healthy() {
+        let report = audit(&[("a", Ok(STRONG), 1), ("b", Ok("Qp4$hN8&zKe1^Lw6"), 1)]).unwrap();
+        assert!(report.is_healthy());
+        assert!(report
+            .to_text()
+            .contains("No reused, weak or stale secrets found."));
+    }
+}
@@ -0,0 +1,244 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::ErrorKind;
+
+use crate::crypto::{self, Cipher, KeypostKsf, Padding};
+use crate::util;
+
+const CONFIG_FILE: &str = "config.json";
+
+// Per-profile settings, read from config.json in the profile's directory. Missing fields (or a
+// missing file) fall back to the defaults below.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct Config {
+    pub server_url: String,
+    pub tls: TlsConfig,
+    pub http: HttpConfig,
+    pub password_policy: PasswordPolicyConfig,
+    pub hibp_file: Option<String>, // local HIBP SHA-1 dataset or compact index
+    pub audit: AuditConfig,
+    pub privacy: PrivacyConfig,
+    pub padding: Padding, // "none", "padme" or "buckets"
+    pub cipher: Cipher,   // "xchacha20poly1305", "aes256gcmsiv" or "chacha20poly1305"
+    pub ksf: KsfConfig,
+    pub server_key: ServerKeyConfig,
+    pub lock_timeout_secs: u64, // how long to wait for another process using the profile
+    pub allow_legacy_channel: bool, // accept servers that only support secure channel version 1
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            server_url: "http://localhost:8000".to_string(),
+            tls: TlsConfig::default(),
+            http: HttpConfig::default(),
+            password_policy: PasswordPolicyConfig::default(),
+            hibp_file: None,
+            audit: AuditConfig::default(),
+            privacy: PrivacyConfig::default(),
+            padding: Padding::default(),
+            cipher: Cipher::default(),
+            ksf: KsfConfig::default(),
+            server_key: ServerKeyConfig::default(),
+            lock_timeout_secs: 10,
+            allow_legacy_channel: false,
+        }
+    }
+}
+
+// See tls.rs. Plain HTTP is only allowed for loopback servers without any of these settings.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct TlsConfig {
+    pub ca_bundle: Option<String>, // PEM file; when set, only these CAs are trusted
+    pub spki_pins: Vec<String>,    // "sha256/<base64>" of the server certificate's public key
+}
+
+// See http.rs. Retries only apply to requests that are safe to repeat (start steps and reads).
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct HttpConfig {
+    pub connect_timeout_secs: u64,
+    pub timeout_secs: u64, // whole request, including reading the response
+    pub max_retries: u32,
+    pub retry_base_delay_ms: u64, // first backoff, doubled on each retry and jittered
+    pub max_retry_delay_secs: u64, // longest backoff or Retry-After the client will wait
+    pub proxy: ProxyConfig,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            connect_timeout_secs: 10,
+            timeout_secs: 30,
+            max_retries: 3,
+            retry_base_delay_ms: 250,
+            max_retry_delay_secs: 30,
+            proxy: ProxyConfig::default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct ProxyConfig {
+    pub url: Option<String>, // http://, https://, socks5:// or socks5h:// proxy for all requests
+    pub no_proxy: Option<String>, // comma-separated hosts, domains or CIDRs that bypass `url`
+    pub from_env: bool,      // without `url`, use HTTP_PROXY, HTTPS_PROXY, ALL_PROXY and NO_PROXY
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        ProxyConfig {
+            url: None,
+            no_proxy: None,
+            from_env: true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct PasswordPolicyConfig {
+    pub min_length: usize,
+    pub min_score: u8, // 0 (too guessable) to 4 (very unguessable)
+    pub warn_on_login: bool,
+}
+
+impl Default for PasswordPolicyConfig {
+    fn default() -> Self {
+        PasswordPolicyConfig {
+            min_length: 12,
+            min_score: 3,
+            warn_on_login: true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct AuditConfig {
+    pub min_entropy_bits: f64,
+    pub max_age_days: u64,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        AuditConfig {
+            min_entropy_bits: 60.0,
+            max_age_days: 365,
+        }
+    }
+}
+
+// Opt-in privacy mode: the account is registered and logged into under a blinded credential
+// identifier instead of the email, and locker requests carry no email at all. It must be enabled
+// before registering, since the identifier is bound into the OPAQUE registration.
+//
+// The identifier is an HMAC of the email keyed by `server_salt`, which has no default: with a
+// well-known salt, the server could recover emails by hashing guesses. The operator hands out a
+// random salt to the deployment's users; it isn't a secret they need to guard like a password, but
+// the blinding only hides emails from a server that doesn't know it.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct PrivacyConfig {
+    pub enabled: bool,
+    pub server_salt: Option<String>,
+}
+
+impl PrivacyConfig {
+    /// The identifier the server knows this account by.
+    pub fn credential_id(&self, email: &str) -> Result<String, String> {
+        match (self.enabled, self.server_salt.as_deref()) {
+            (false, _) => Ok(email.to_string()),
+            (true, Some(salt)) if !salt.is_empty() => Ok(crypto::blind_credential_id(email, salt)),
+            (true, _) => Err(
+                "privacy.enabled requires privacy.server_salt in config.json: ask \
+                 the server operator for the deployment's salt. (Profiles that enabled privacy \
+                 before it was required used \"http://localhost:8000\".)"
+                    .to_string(),
+            ),
+        }
+    }
+
+    /// The email to attach to locker requests, if any.
+    pub fn locker_email<'a>(&self, email: &'a str) -> Option<&'a str> {
+        match self.enabled {
+            true => None,
+            false => Some(email),
+        }
+    }
+}
+
+// Key stretching for the account password, used for new registrations and `migrate-ksf`. Logins
+// use whatever suite the server announces for the account, so changing this never locks anyone
+// out. Argon2id requires building with the `argon2` feature, and is then the default.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct KsfConfig {
+    pub algorithm: KsfAlgorithm,
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum KsfAlgorithm {
+    #[cfg_attr(not(feature = "argon2"), default)]
+    Identity,
+    #[cfg_attr(feature = "argon2", default)]
+    Argon2id,
+}
+
+impl Default for KsfConfig {
+    fn default() -> Self {
+        KsfConfig {
+            algorithm: KsfAlgorithm::default(),
+            memory_kib: 64 * 1024,
+            iterations: 3,
+            parallelism: 1,
+        }
+    }
+}
+
+impl KsfConfig {
+    pub fn build(&self) -> Result<KeypostKsf, String> {
+        match self.algorithm {
+            KsfAlgorithm::Identity => Ok(KeypostKsf::Identity),
+            #[cfg(feature = "argon2")]
+            KsfAlgorithm::Argon2id => {
+                KeypostKsf::argon2id(self.memory_kib, self.iterations, self.parallelism)
+                    .map_err(|err| err.to_string())
+            }
+            #[cfg(not(feature = "argon2"))]
+            KsfAlgorithm::Argon2id => Err(
+                "ksf.algorithm is argon2id, but keypost-cli was built without the argon2 feature"
+                    .to_string(),
+            ),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct ServerKeyConfig {
+    // The operator's offline ECDSA P-256 root key (base64 SEC1), which may sign key rotations.
+    pub root_key: Option<String>,
+}
+
+pub fn load() -> Result<Config, String> {
+    let path = util::dirs::config_path(CONFIG_FILE).map_err(|err| err.to_string())?;
+    match fs::read(&path) {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .map_err(|err| format!("Could not parse {}: {}", CONFIG_FILE, err)),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(Config::default()),
+        Err(err) => Err(format!("Could not read {}: {:?}", path.display(), err)),
+    }
+}
+
+/// The email to attach to locker requests under the current profile's privacy setting.
+pub fn locker_email(email: &str) -> Result<Option<&str>, String> {
+    Ok(load()?.privacy.locker_email(email))
+}
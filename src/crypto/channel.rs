@@ -0,0 +1,250 @@
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use zeroize::Zeroize;
+
+use crate::models::CliError;
+
+// Session-layer protocol versions negotiated with the server at login:
+//   1: legacy, messages encrypted under the raw session key with the u32 id repeated as nonce
+//   2: SecureChannel below
+pub const LEGACY_CHANNEL_VERSION: u32 = 1;
+pub const CHANNEL_VERSION: u32 = 2;
+
+const CLIENT_TO_SERVER_INFO: &[u8] = b"keypost channel v2 client->server";
+const SERVER_TO_CLIENT_INFO: &[u8] = b"keypost channel v2 server->client";
+const SESSION_ID_INFO: &[u8] = b"keypost channel v2 session id";
+pub const SESSION_ID_LEN: usize = 20;
+const COUNTER_LEN: usize = 8;
+
+// A pair of one-way AEAD channels keyed from an OPAQUE session key. Each direction has its own
+// HKDF-derived key and a message counter that doubles as the nonce, so nonces never repeat and
+// messages that arrive replayed, dropped or out of order are rejected.
+pub struct SecureChannel {
+    send_key: [u8; 32],
+    recv_key: [u8; 32],
+    send_counter: u64,
+    recv_counter: u64,
+}
+
+impl SecureChannel {
+    /// The client end of a channel; `context` (e.g. the server's id for the exchange) is mixed
+    /// into both keys so that channels from different exchanges are independent.
+    pub fn client(session_key: &[u8], context: &[u8]) -> SecureChannel {
+        SecureChannel {
+            send_key: derive_key(session_key, CLIENT_TO_SERVER_INFO, context),
+            recv_key: derive_key(session_key, SERVER_TO_CLIENT_INFO, context),
+            send_counter: 0,
+            recv_counter: 0,
+        }
+    }
+
+    /// Encrypt the next outgoing message as counter || ciphertext.
+    pub fn seal(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, CliError> {
+        let counter = self.send_counter.to_be_bytes();
+        let ciphertext = ChaCha20Poly1305::new(Key::from_slice(&self.send_key))
+            .encrypt(
+                &counter_nonce(&counter),
+                Payload {
+                    msg: plaintext,
+                    aad: &channel_aad(&counter),
+                },
+            )
+            .map_err(|_| CliError::MalformedCiphertextError("encryption failed".to_string()))?;
+        self.send_counter += 1;
+        Ok([counter.to_vec(), ciphertext].concat())
+    }
+
+    /// Decrypt the next incoming message, which must carry exactly the expected counter.
+    pub fn open(&mut self, message: &[u8]) -> Result<Vec<u8>, CliError> {
+        if message.len() < COUNTER_LEN {
+            return Err(CliError::MalformedCiphertextError(
+                "channel message too short".to_string(),
+            ));
+        }
+        let (counter, ciphertext) = message.split_at(COUNTER_LEN);
+        let mut counter_bytes = [0u8; COUNTER_LEN];
+        counter_bytes.copy_from_slice(counter);
+        let received = u64::from_be_bytes(counter_bytes);
+        if received != self.recv_counter {
+            return Err(CliError::ReplayError(received, self.recv_counter));
+        }
+        let plaintext = ChaCha20Poly1305::new(Key::from_slice(&self.recv_key))
+            .decrypt(
+                &counter_nonce(&counter_bytes),
+                Payload {
+                    msg: ciphertext,
+                    aad: &channel_aad(&counter_bytes),
+                },
+            )
+            .map_err(|_| CliError::TamperError)?;
+        self.recv_counter += 1;
+        Ok(plaintext)
+    }
+}
+
+impl Drop for SecureChannel {
+    fn drop(&mut self) {
+        self.send_key.zeroize();
+        self.recv_key.zeroize();
+    }
+}
+
+// The bearer token for a logged-in session, derived rather than encrypted so that no nonce is
+// involved. The server derives the same value from its copy of the session key.
+pub fn derive_session_id(session_key: &[u8], context: &[u8]) -> [u8; SESSION_ID_LEN] {
+    let mut session_id = [0u8; SESSION_ID_LEN];
+    Hkdf::<Sha256>::new(None, session_key)
+        .expand(&[SESSION_ID_INFO, context].concat(), &mut session_id)
+        .expect("20 bytes is a valid HKDF-SHA256 output length");
+    session_id
+}
+
+// The context for the channel and session id of a login: the exchange id and both the version the
+// client offered and the one the server chose. A man-in-the-middle who changes either ends up with
+// different keys from the server's, so the login fails instead of being silently downgraded.
+pub fn login_context(exchange_id: u32, offered: u32, chosen: u32) -> Vec<u8> {
+    [
+        exchange_id.to_be_bytes(),
+        offered.to_be_bytes(),
+        chosen.to_be_bytes(),
+    ]
+    .concat()
+}
+
+fn derive_key(session_key: &[u8], direction: &[u8], context: &[u8]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(None, session_key)
+        .expand(&[direction, context].concat(), &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    key
+}
+
+fn counter_nonce(counter: &[u8; COUNTER_LEN]) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[12 - COUNTER_LEN..].copy_from_slice(counter);
+    Nonce::clone_from_slice(&nonce)
+}
+
+fn channel_aad(counter: &[u8; COUNTER_LEN]) -> Vec<u8> {
+    [&CHANNEL_VERSION.to_be_bytes()[..], counter].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SESSION_KEY: [u8; 64] = [3u8; 64];
+
+    // The server's end of the channel: the client's keys, with the directions swapped.
+    fn server(session_key: &[u8], context: &[u8]) -> SecureChannel {
+        SecureChannel {
+            send_key: derive_key(session_key, SERVER_TO_CLIENT_INFO, context),
+            recv_key: derive_key(session_key, CLIENT_TO_SERVER_INFO, context),
+            send_counter: 0,
+            recv_counter: 0,
+        }
+    }
+
+    fn pair() -> (SecureChannel, SecureChannel) {
+        let context = login_context(42, CHANNEL_VERSION, CHANNEL_VERSION);
+        (
+            SecureChannel::client(&SESSION_KEY, &context),
+            server(&SESSION_KEY, &context),
+        )
+    }
+
+    #[test]
+    fn messages_round_trip_in_both_directions() {
+        let (mut client, mut server) = pair();
+        for i in 0..3u8 {
+            let request = client.seal(&[i]).unwrap();
+            assert_eq!(server.open(&request).unwrap(), [i]);
+            let response = server.seal(&[i, i]).unwrap();
+            assert_eq!(client.open(&response).unwrap(), [i, i]);
+        }
+    }
+
+    #[test]
+    fn rejects_replayed_messages() {
+        let (mut client, mut server) = pair();
+        let message = server.seal(b"first").unwrap();
+        client.open(&message).unwrap();
+        assert!(matches!(
+            client.open(&message),
+            Err(CliError::ReplayError(0, 1))
+        ));
+    }
+
+    #[test]
+    fn rejects_messages_out_of_order() {
+        let (mut client, mut server) = pair();
+        let first = server.seal(b"first").unwrap();
+        let second = server.seal(b"second").unwrap();
+        assert!(matches!(
+            client.open(&second),
+            Err(CliError::ReplayError(1, 0))
+        ));
+        // Rejected messages don't advance the channel.
+        assert_eq!(client.open(&first).unwrap(), b"first");
+        assert_eq!(client.open(&second).unwrap(), b"second");
+    }
+
+    #[test]
+    fn rejects_tampered_messages() {
+        let (mut client, mut server) = pair();
+        let message = server.seal(b"first").unwrap();
+        for bit in 0..8 {
+            let mut flipped = message.clone();
+            flipped[COUNTER_LEN + 2] ^= 1 << bit;
+            assert!(matches!(client.open(&flipped), Err(CliError::TamperError)));
+        }
+        // A later message relabelled with the expected counter doesn't decrypt either.
+        let mut relabelled = server.seal(b"second").unwrap();
+        relabelled[..COUNTER_LEN].copy_from_slice(&0u64.to_be_bytes());
+        assert!(matches!(
+            client.open(&relabelled),
+            Err(CliError::TamperError)
+        ));
+        assert!(matches!(
+            client.open(&message[..COUNTER_LEN - 1]),
+            Err(CliError::MalformedCiphertextError(_))
+        ));
+        assert_eq!(client.open(&message).unwrap(), b"first");
+    }
+
+    #[test]
+    fn login_context_binds_the_exchange_and_both_versions() {
+        let contexts = [
+            login_context(42, CHANNEL_VERSION, CHANNEL_VERSION),
+            login_context(43, CHANNEL_VERSION, CHANNEL_VERSION),
+            login_context(42, LEGACY_CHANNEL_VERSION, CHANNEL_VERSION),
+            login_context(42, CHANNEL_VERSION, LEGACY_CHANNEL_VERSION),
+        ];
+        for (i, a) in contexts.iter().enumerate() {
+            for b in contexts[i + 1..].iter() {
+                assert_ne!(a, b);
+                assert_ne!(
+                    derive_session_id(&SESSION_KEY, a),
+                    derive_session_id(&SESSION_KEY, b)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn a_downgraded_version_leaves_the_ends_unable_to_talk() {
+        // The server saw the client offer version 1 (a man-in-the-middle rewrote the offer).
+        let mut client = SecureChannel::client(
+            &SESSION_KEY,
+            &login_context(42, CHANNEL_VERSION, CHANNEL_VERSION),
+        );
+        let mut server = server(
+            &SESSION_KEY,
+            &login_context(42, LEGACY_CHANNEL_VERSION, CHANNEL_VERSION),
+        );
+        let request = client.seal(b"challenge").unwrap();
+        assert!(matches!(server.open(&request), Err(CliError::TamperError)));
+    }
+}
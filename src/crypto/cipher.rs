@@ -0,0 +1,92 @@
+use aes_gcm_siv::Aes256GcmSiv;
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, XChaCha20Poly1305};
+use serde::{Deserialize, Serialize};
+
+use crate::models::CliError;
+
+// AEAD algorithms a locker envelope can name in its header.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Cipher {
+    ChaCha20Poly1305, // legacy: 96-bit random nonces
+    #[default]
+    XChaCha20Poly1305, // 192-bit random nonces, no birthday bound concerns
+    Aes256GcmSiv,     // nonce-misuse resistant
+}
+
+pub const KEY_LEN: usize = 32;
+
+impl Cipher {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Cipher::ChaCha20Poly1305 => 1,
+            Cipher::XChaCha20Poly1305 => 2,
+            Cipher::Aes256GcmSiv => 3,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Result<Cipher, CliError> {
+        match byte {
+            1 => Ok(Cipher::ChaCha20Poly1305),
+            2 => Ok(Cipher::XChaCha20Poly1305),
+            3 => Ok(Cipher::Aes256GcmSiv),
+            _ => Err(CliError::MalformedCiphertextError(format!(
+                "unknown cipher {}",
+                byte
+            ))),
+        }
+    }
+
+    pub fn nonce_len(self) -> usize {
+        match self {
+            Cipher::XChaCha20Poly1305 => 24,
+            Cipher::ChaCha20Poly1305 | Cipher::Aes256GcmSiv => 12,
+        }
+    }
+
+    pub fn encrypt(self, key: &[u8], nonce: &[u8], payload: Payload) -> Result<Vec<u8>, CliError> {
+        let key = cipher_key(key)?;
+        self.check_nonce(nonce)?;
+        let result = match self {
+            Cipher::ChaCha20Poly1305 => ChaCha20Poly1305::new(key).encrypt(nonce.into(), payload),
+            Cipher::XChaCha20Poly1305 => XChaCha20Poly1305::new(key).encrypt(nonce.into(), payload),
+            Cipher::Aes256GcmSiv => Aes256GcmSiv::new(key).encrypt(nonce.into(), payload),
+        };
+        result.map_err(|_| CliError::MalformedCiphertextError("encryption failed".to_string()))
+    }
+
+    pub fn decrypt(self, key: &[u8], nonce: &[u8], payload: Payload) -> Result<Vec<u8>, CliError> {
+        let key = cipher_key(key)?;
+        self.check_nonce(nonce)?;
+        let result = match self {
+            Cipher::ChaCha20Poly1305 => ChaCha20Poly1305::new(key).decrypt(nonce.into(), payload),
+            Cipher::XChaCha20Poly1305 => XChaCha20Poly1305::new(key).decrypt(nonce.into(), payload),
+            Cipher::Aes256GcmSiv => Aes256GcmSiv::new(key).decrypt(nonce.into(), payload),
+        };
+        result.map_err(|_| CliError::TamperError)
+    }
+
+    fn check_nonce(self, nonce: &[u8]) -> Result<(), CliError> {
+        match nonce.len() == self.nonce_len() {
+            true => Ok(()),
+            false => Err(CliError::MalformedCiphertextError(format!(
+                "nonce is {} bytes, expected {}",
+                nonce.len(),
+                self.nonce_len()
+            ))),
+        }
+    }
+}
+
+// Keys may be longer than KEY_LEN (export keys are 64 bytes); the first KEY_LEN bytes are used. A
+// shorter one (e.g. from a damaged key file) is refused.
+pub fn cipher_key(key: &[u8]) -> Result<&Key, CliError> {
+    key.get(..KEY_LEN).map(Key::from_slice).ok_or_else(|| {
+        CliError::InvalidKeyError(format!(
+            "{} bytes, expected at least {}",
+            key.len(),
+            KEY_LEN
+        ))
+    })
+}
@@ -0,0 +1,210 @@
+use generic_array::{ArrayLength, GenericArray};
+use opaque_ke::errors::InternalError;
+use opaque_ke::ksf::Ksf;
+
+use crate::models::CliError;
+
+// The key stretching function applied to the OPRF output during account registration and login.
+// An account's KSF is fixed when it registers and is announced to the server as a suite string,
+// which the server hands back at login:
+//   "identity"                        no stretching (accounts registered before Argon2 support)
+//   "argon2id;m=<KiB>,t=<passes>,p=<lanes>"
+// Lockers are keyed by the high-entropy export key, so their exchanges always use Identity.
+pub const IDENTITY_SUITE: &str = "identity";
+#[cfg(feature = "argon2")]
+const ARGON2ID_SUITE: &str = "argon2id";
+// Upper bounds on what a server-announced suite may ask of this machine, so that a malicious or
+// broken server can't make every login hang or run out of memory.
+#[cfg(feature = "argon2")]
+const MAX_MEMORY_KIB: u32 = 4 * 1024 * 1024;
+#[cfg(feature = "argon2")]
+const MAX_ITERATIONS: u32 = 64;
+#[cfg(feature = "argon2")]
+const MAX_PARALLELISM: u32 = 16;
+
+#[derive(Clone, Debug, Default)]
+pub enum KeypostKsf {
+    #[default]
+    Identity,
+    #[cfg(feature = "argon2")]
+    Argon2id(argon2::Params),
+}
+
+impl Ksf for KeypostKsf {
+    fn hash<L: ArrayLength<u8>>(
+        &self,
+        input: GenericArray<u8, L>,
+    ) -> Result<GenericArray<u8, L>, InternalError> {
+        match self {
+            KeypostKsf::Identity => Ok(input),
+            // Same construction as opaque-ke's own Argon2 KSF: a fixed all-zero salt, since the
+            // OPRF output is already unique to the account.
+            #[cfg(feature = "argon2")]
+            KeypostKsf::Argon2id(params) => {
+                let mut output = GenericArray::default();
+                argon2::Argon2::new(
+                    argon2::Algorithm::Argon2id,
+                    argon2::Version::V0x13,
+                    params.clone(),
+                )
+                .hash_password_into(&input, &[0; argon2::RECOMMENDED_SALT_LEN], &mut output)
+                .map_err(|_| InternalError::KsfError)?;
+                Ok(output)
+            }
+        }
+    }
+}
+
+impl KeypostKsf {
+    #[cfg(feature = "argon2")]
+    pub fn argon2id(memory_kib: u32, iterations: u32, parallelism: u32) -> Result<Self, CliError> {
+        let limits = [
+            ("memory", memory_kib, MAX_MEMORY_KIB, " KiB"),
+            ("iterations", iterations, MAX_ITERATIONS, ""),
+            ("parallelism", parallelism, MAX_PARALLELISM, ""),
+        ];
+        for (name, value, max, unit) in limits.iter() {
+            if value > max {
+                return Err(CliError::UnsupportedKsfError(format!(
+                    "argon2id {} of {}{} exceeds the limit of {}{}",
+                    name, value, unit, max, unit
+                )));
+            }
+        }
+        argon2::Params::new(memory_kib, iterations, parallelism, None)
+            .map(KeypostKsf::Argon2id)
+            .map_err(|err| CliError::UnsupportedKsfError(format!("argon2id: {}", err)))
+    }
+
+    pub fn suite(&self) -> String {
+        match self {
+            KeypostKsf::Identity => IDENTITY_SUITE.to_string(),
+            #[cfg(feature = "argon2")]
+            KeypostKsf::Argon2id(params) => format!(
+                "{};m={},t={},p={}",
+                ARGON2ID_SUITE,
+                params.m_cost(),
+                params.t_cost(),
+                params.p_cost()
+            ),
+        }
+    }
+
+    pub fn from_suite(suite: &str) -> Result<Self, CliError> {
+        let mut parts = suite.splitn(2, ';');
+        match (parts.next(), parts.next()) {
+            (Some(IDENTITY_SUITE), None) => Ok(KeypostKsf::Identity),
+            #[cfg(feature = "argon2")]
+            (Some(ARGON2ID_SUITE), Some(params)) => {
+                let (mut m, mut t, mut p) = (None, None, None);
+                for param in params.split(',') {
+                    let mut kv = param.splitn(2, '=');
+                    let value = kv.next().zip(kv.next()).and_then(|(key, value)| {
+                        value.parse::<u32>().ok().map(|value| (key, value))
+                    });
+                    let slot = match value {
+                        Some(("m", value)) => (&mut m, value),
+                        Some(("t", value)) => (&mut t, value),
+                        Some(("p", value)) => (&mut p, value),
+                        _ => return Err(CliError::UnsupportedKsfError(suite.to_string())),
+                    };
+                    // Each parameter must appear exactly once.
+                    if slot.0.replace(slot.1).is_some() {
+                        return Err(CliError::UnsupportedKsfError(suite.to_string()));
+                    }
+                }
+                match (m, t, p) {
+                    (Some(m), Some(t), Some(p)) => KeypostKsf::argon2id(m, t, p),
+                    _ => Err(CliError::UnsupportedKsfError(suite.to_string())),
+                }
+            }
+            #[cfg(not(feature = "argon2"))]
+            (Some("argon2id"), Some(_)) => Err(CliError::UnsupportedKsfError(format!(
+                "{} (keypost-cli was built without the argon2 feature)",
+                suite
+            ))),
+            _ => Err(CliError::UnsupportedKsfError(suite.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejected(suite: &str) -> bool {
+        matches!(
+            KeypostKsf::from_suite(suite),
+            Err(CliError::UnsupportedKsfError(_))
+        )
+    }
+
+    #[test]
+    fn identity_suite_round_trips() {
+        let ksf = KeypostKsf::from_suite(IDENTITY_SUITE).unwrap();
+        assert_eq!(ksf.suite(), IDENTITY_SUITE);
+    }
+
+    #[test]
+    fn rejects_malformed_suites() {
+        for suite in [
+            "",
+            "identity;m=1",
+            "Identity",
+            "scrypt;n=1",
+            ";",
+            "argon2id",
+        ]
+        .iter()
+        {
+            assert!(rejected(suite), "{}", suite);
+        }
+    }
+
+    #[cfg(feature = "argon2")]
+    #[test]
+    fn argon2id_suite_round_trips() {
+        let suite = "argon2id;m=65536,t=3,p=1";
+        assert_eq!(KeypostKsf::from_suite(suite).unwrap().suite(), suite);
+        // Parameter order doesn't matter; the suite is re-encoded in canonical order.
+        let reordered = KeypostKsf::from_suite("argon2id;p=1,t=3,m=65536").unwrap();
+        assert_eq!(reordered.suite(), suite);
+    }
+
+    #[cfg(feature = "argon2")]
+    #[test]
+    fn rejects_malformed_argon2id_suites() {
+        let suites = [
+            "argon2id;",
+            "argon2id;m=65536,t=3",
+            "argon2id;m=65536,t=3,p=1,x=1",
+            "argon2id;m=65536,t=3,p=1,p=2",
+            "argon2id;m=65536,t=three,p=1",
+            "argon2id;m=65536,t=-3,p=1",
+            "argon2id;m=65536;t=3;p=1",
+            "argon2id;m=65536,t=0,p=1",
+        ];
+        for suite in suites.iter() {
+            assert!(rejected(suite), "{}", suite);
+        }
+    }
+
+    #[cfg(feature = "argon2")]
+    #[test]
+    fn rejects_oversized_argon2id_suites() {
+        let suites = [
+            format!("argon2id;m={},t=3,p=1", MAX_MEMORY_KIB + 1),
+            format!("argon2id;m=65536,t={},p=1", MAX_ITERATIONS + 1),
+            format!("argon2id;m=65536,t={},p=1", u32::MAX),
+            format!("argon2id;m=65536,t=3,p={}", MAX_PARALLELISM + 1),
+        ];
+        for suite in suites.iter() {
+            assert!(rejected(suite), "{}", suite);
+        }
+        let largest = format!(
+            "argon2id;m={},t={},p={}",
+            MAX_MEMORY_KIB, MAX_ITERATIONS, MAX_PARALLELISM
+        );
+        assert!(KeypostKsf::from_suite(&largest).is_ok());
+    }
+}
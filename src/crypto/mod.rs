@@ -1,40 +1,192 @@
+pub mod channel;
+pub mod cipher;
+pub mod ksf;
 pub mod opaque;
+pub mod padding;
 
+pub use channel::SecureChannel;
+pub use cipher::Cipher;
+pub use ksf::KeypostKsf;
 pub use opaque::*;
+pub use padding::Padding;
 
-use chacha20poly1305::aead::{Aead, NewAead};
-use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use chacha20poly1305::aead::Payload;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac, NewMac};
 use opaque_ke::rand::rngs::OsRng;
 use opaque_ke::rand::RngCore;
+use sha2::Sha256;
+use zeroize::Zeroize;
 
-pub fn encrypt_bytes(nonce: &[u8], key: &[u8], plaintext: &[u8]) -> Vec<u8> {
-    encrypt(&nonce[..12], key, plaintext).expect("Could not encrypt bytes!")
+use crate::models::CliError;
+
+const LOCKER_ID_INFO: &[u8] = b"keypost locker id v1";
+const CREDENTIAL_ID_INFO: &[u8] = b"keypost credential id v1";
+
+pub fn encrypt_bytes(nonce: &[u8], key: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, CliError> {
+    Cipher::ChaCha20Poly1305.encrypt(key, nonce, plaintext.into())
 }
 
-pub fn encrypt_bytes_with_u32_nonce(u32_nonce: &u32, key: &[u8], plaintext: &[u8]) -> Vec<u8> {
+// Only used by the legacy (version 1) session protocol; see channel.rs.
+pub fn encrypt_bytes_with_u32_nonce(
+    u32_nonce: &u32,
+    key: &[u8],
+    plaintext: &[u8],
+) -> Result<Vec<u8>, CliError> {
     let nonce = expand_u32_nonce(u32_nonce);
     encrypt_bytes(&nonce, key, plaintext)
 }
 
-// Given a key and plaintext, produce an AEAD ciphertext along with a nonce
-pub fn encrypt_locker(key: &[u8], plaintext: &[u8]) -> Vec<u8> {
+// Locker ciphertext envelope: header || nonce || AEAD ciphertext, where the header is
+//   v1: LOCKER_MAGIC || 1                      (ChaCha20Poly1305, no padding)
+//   v2: LOCKER_MAGIC || 2 || padding           (ChaCha20Poly1305)
+//   v3: LOCKER_MAGIC || 3 || cipher || padding
+// The header, the locker name and the account are authenticated as associated data, so a
+// ciphertext served for the wrong locker or account fails to decrypt. Legacy lockers are a bare
+// ChaCha20Poly1305 nonce || ciphertext, which binds nothing, so they are refused here and only read
+// (with decrypt_bytes) on the way to being rewrapped.
+const LOCKER_MAGIC: &[u8; 3] = b"KPL";
+pub const LOCKER_FORMAT_VERSION: u8 = 3;
+
+// Given a key and plaintext, produce an AEAD envelope bound to the locker and account
+pub fn encrypt_locker(
+    key: &[u8],
+    locker_id: &str,
+    account_id: &str,
+    plaintext: &[u8],
+    cipher: Cipher,
+    padding: Padding,
+) -> Result<Vec<u8>, CliError> {
     let mut rng = OsRng;
-    let mut nonce_bytes = [0u8; 12];
+    let mut nonce_bytes = vec![0u8; cipher.nonce_len()];
     rng.fill_bytes(&mut nonce_bytes);
 
-    let ciphertext = encrypt(&nonce_bytes, key, plaintext).expect("Could not encrypt locker!");
-    [nonce_bytes.to_vec(), ciphertext].concat()
+    let header = [
+        &LOCKER_MAGIC[..],
+        &[LOCKER_FORMAT_VERSION, cipher.to_byte(), padding.to_byte()],
+    ]
+    .concat();
+    let aad = locker_aad(&header, locker_id, account_id);
+    let ciphertext = cipher.encrypt(
+        key,
+        &nonce_bytes,
+        Payload {
+            msg: &padding.pad(plaintext),
+            aad: &aad,
+        },
+    )?;
+    Ok([header, nonce_bytes, ciphertext].concat())
+}
+
+// Decrypt a locker envelope to recover the original plaintext
+pub fn decrypt_locker(
+    key: &[u8],
+    locker_id: &str,
+    account_id: &str,
+    ciphertext: &[u8],
+) -> Result<Vec<u8>, CliError> {
+    if !ciphertext.starts_with(LOCKER_MAGIC) {
+        return Err(CliError::LegacyCiphertextError);
+    }
+    let (header_len, cipher, padding) = parse_locker_header(ciphertext)?;
+    if ciphertext.len() < header_len + cipher.nonce_len() {
+        return Err(CliError::MalformedCiphertextError(
+            "envelope too short".to_string(),
+        ));
+    }
+    let (header, rest) = ciphertext.split_at(header_len);
+    let (nonce, ciphertext) = rest.split_at(cipher.nonce_len());
+    let aad = locker_aad(header, locker_id, account_id);
+    let padded = cipher.decrypt(
+        key,
+        nonce,
+        Payload {
+            msg: ciphertext,
+            aad: &aad,
+        },
+    )?;
+    padding.unpad(&padded)
+}
+
+// Returns the header length and the cipher and padding it names.
+fn parse_locker_header(envelope: &[u8]) -> Result<(usize, Cipher, Padding), CliError> {
+    let field = |i: usize| {
+        envelope
+            .get(LOCKER_MAGIC.len() + i)
+            .copied()
+            .ok_or_else(|| {
+                CliError::MalformedCiphertextError("envelope header too short".to_string())
+            })
+    };
+    match field(0)? {
+        1 => Ok((
+            LOCKER_MAGIC.len() + 1,
+            Cipher::ChaCha20Poly1305,
+            Padding::None,
+        )),
+        2 => Ok((
+            LOCKER_MAGIC.len() + 2,
+            Cipher::ChaCha20Poly1305,
+            Padding::from_byte(field(1)?)?,
+        )),
+        3 => Ok((
+            LOCKER_MAGIC.len() + 3,
+            Cipher::from_byte(field(1)?)?,
+            Padding::from_byte(field(2)?)?,
+        )),
+        version => Err(CliError::MalformedCiphertextError(format!(
+            "unsupported locker format version {}",
+            version
+        ))),
+    }
+}
+
+// Decrypt using a key and a ciphertext (nonce included), without associated data
+pub fn decrypt_bytes(key: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, CliError> {
+    let nonce_len = Cipher::ChaCha20Poly1305.nonce_len();
+    if ciphertext.len() < nonce_len {
+        return Err(CliError::MalformedCiphertextError(
+            "ciphertext too short".to_string(),
+        ));
+    }
+    let (nonce, ciphertext) = ciphertext.split_at(nonce_len);
+    Cipher::ChaCha20Poly1305.decrypt(key, nonce, ciphertext.into())
+}
+
+// Length-prefixed so that no two (locker, account) pairs produce the same associated data.
+fn locker_aad(header: &[u8], locker_id: &str, account_id: &str) -> Vec<u8> {
+    let mut aad = header.to_vec();
+    for field in [locker_id, account_id].iter() {
+        aad.extend_from_slice(&(field.len() as u32).to_be_bytes());
+        aad.extend_from_slice(field.as_bytes());
+    }
+    aad
 }
 
-// Decrypt using a key and a ciphertext (nonce included) to recover the original plaintext
-pub fn decrypt_locker(key: &[u8], ciphertext: &[u8]) -> Vec<u8> {
-    let cipher = ChaCha20Poly1305::new(Key::from_slice(&key[..32]));
-    cipher
-        .decrypt(
-            Nonce::from_slice(&ciphertext[..12]),
-            ciphertext[12..].as_ref(),
-        )
-        .unwrap()
+// Derive the id a locker is stored under on the server from its name, so that the server only
+// ever sees an opaque identifier: HMAC-SHA256(HKDF(export_key), name).
+pub fn blind_locker_id(export_key: &[u8], locker_name: &str) -> String {
+    let mut id_key = [0u8; 32];
+    Hkdf::<Sha256>::new(None, export_key)
+        .expand(LOCKER_ID_INFO, &mut id_key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    let mut mac = Hmac::<Sha256>::new_from_slice(&id_key).expect("HMAC accepts keys of any length");
+    id_key.zeroize();
+    mac.update(locker_name.as_bytes());
+    base64::encode_config(mac.finalize().into_bytes(), base64::URL_SAFE_NO_PAD)
+}
+
+// The identifier an account is registered under in privacy mode. The salt is specific to the
+// deployment (see PrivacyConfig), so the same email yields unrelated identifiers on different
+// servers.
+pub fn blind_credential_id(email: &str, server_salt: &str) -> String {
+    let mut id_key = [0u8; 32];
+    Hkdf::<Sha256>::new(None, server_salt.as_bytes())
+        .expand(CREDENTIAL_ID_INFO, &mut id_key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    let mut mac = Hmac::<Sha256>::new_from_slice(&id_key).expect("HMAC accepts keys of any length");
+    mac.update(email.trim().to_lowercase().as_bytes());
+    base64::encode_config(mac.finalize().into_bytes(), base64::URL_SAFE_NO_PAD)
 }
 
 pub fn expand_u32_nonce(u32_nonce: &u32) -> Vec<u8> {
@@ -53,12 +205,92 @@ pub fn rand_bytes() -> Vec<u8> {
     bytes.to_vec()
 }
 
-fn encrypt(
-    nonce_bytes: &[u8],
-    key: &[u8],
-    plaintext: &[u8],
-) -> Result<Vec<u8>, chacha20poly1305::aead::Error> {
-    let cipher = ChaCha20Poly1305::new(Key::from_slice(&key[..32]));
-    let nonce = Nonce::from_slice(nonce_bytes);
-    cipher.encrypt(nonce, plaintext.as_ref())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 64] = [7u8; 64];
+
+    fn seal(cipher: Cipher, padding: Padding) -> Vec<u8> {
+        encrypt_locker(&KEY, "github", "foo@bar.com", b"hunter2", cipher, padding).unwrap()
+    }
+
+    #[test]
+    fn lockers_round_trip_with_every_cipher_and_padding() {
+        let ciphers = [
+            Cipher::ChaCha20Poly1305,
+            Cipher::XChaCha20Poly1305,
+            Cipher::Aes256GcmSiv,
+        ];
+        for cipher in ciphers.iter() {
+            for padding in [Padding::None, Padding::Padme, Padding::Buckets].iter() {
+                let envelope = seal(*cipher, *padding);
+                let plaintext = decrypt_locker(&KEY, "github", "foo@bar.com", &envelope).unwrap();
+                assert_eq!(plaintext, b"hunter2");
+            }
+        }
+    }
+
+    #[test]
+    fn lockers_are_bound_to_their_name_and_account() {
+        let envelope = seal(Cipher::default(), Padding::default());
+        assert!(matches!(
+            decrypt_locker(&KEY, "gitlab", "foo@bar.com", &envelope),
+            Err(CliError::TamperError)
+        ));
+        assert!(matches!(
+            decrypt_locker(&KEY, "github", "bar@foo.com", &envelope),
+            Err(CliError::TamperError)
+        ));
+        // Moving bytes between the name and the account doesn't help either.
+        let envelope = encrypt_locker(&KEY, "a", "bc", b"x", Cipher::default(), Padding::None);
+        assert!(matches!(
+            decrypt_locker(&KEY, "ab", "c", &envelope.unwrap()),
+            Err(CliError::TamperError)
+        ));
+    }
+
+    #[test]
+    fn locker_headers_are_authenticated() {
+        let mut envelope = seal(Cipher::XChaCha20Poly1305, Padding::Padme);
+        envelope[LOCKER_MAGIC.len() + 2] = Padding::Buckets.to_byte();
+        assert!(matches!(
+            decrypt_locker(&KEY, "github", "foo@bar.com", &envelope),
+            Err(CliError::TamperError)
+        ));
+    }
+
+    #[test]
+    fn legacy_lockers_are_only_read_by_decrypt_bytes() {
+        let nonce = [1u8; 12];
+        let legacy = [
+            nonce.to_vec(),
+            encrypt_bytes(&nonce, &KEY, b"hunter2").unwrap(),
+        ]
+        .concat();
+        assert!(matches!(
+            decrypt_locker(&KEY, "github", "foo@bar.com", &legacy),
+            Err(CliError::LegacyCiphertextError)
+        ));
+        assert_eq!(decrypt_bytes(&KEY, &legacy).unwrap(), b"hunter2");
+    }
+
+    #[test]
+    fn short_keys_and_envelopes_are_errors() {
+        assert!(matches!(
+            encrypt_locker(
+                &KEY[..16],
+                "github",
+                "foo@bar.com",
+                b"x",
+                Cipher::default(),
+                Padding::None
+            ),
+            Err(CliError::InvalidKeyError(_))
+        ));
+        let envelope = seal(Cipher::default(), Padding::None);
+        for len in 0..LOCKER_MAGIC.len() + 3 + Cipher::default().nonce_len() {
+            assert!(decrypt_locker(&KEY, "github", "foo@bar.com", &envelope[..len]).is_err());
+        }
+    }
 }
@@ -1,5 +1,3 @@
-use std::io::ErrorKind;
-
 use opaque_ke::{
     ciphersuite::CipherSuite, errors::ProtocolError, rand::rngs::OsRng, ClientLogin,
     ClientLoginFinishParameters, ClientLoginFinishResult, ClientLoginStartResult,
@@ -7,18 +5,26 @@ use opaque_ke::{
     ClientRegistrationStartResult, CredentialResponse, Identifiers, RegistrationResponse,
 };
 
-use crate::util;
+use crate::crypto::KeypostKsf;
+use crate::models::CliError;
+use crate::server_key;
 
 // The CipherSuite trait allows to specify the underlying primitives
 // that will be used in the OPAQUE protocol
 pub struct DefaultCipherSuite;
 
+// Name of the group DefaultCipherSuite was built with, recorded in each profile.
+#[cfg(feature = "ristretto255")]
+pub const CIPHER_SUITE: &str = "ristretto255";
+#[cfg(not(feature = "ristretto255"))]
+pub const CIPHER_SUITE: &str = "p256";
+
 #[cfg(feature = "ristretto255")]
 impl CipherSuite for DefaultCipherSuite {
     type OprfCs = opaque_ke::Ristretto255;
     type KeGroup = opaque_ke::Ristretto255;
     type KeyExchange = opaque_ke::key_exchange::tripledh::TripleDh;
-    type Ksf = opaque_ke::ksf::Identity;
+    type Ksf = KeypostKsf;
 }
 
 #[cfg(not(feature = "ristretto255"))]
@@ -26,7 +32,7 @@ impl CipherSuite for DefaultCipherSuite {
     type OprfCs = p256::NistP256;
     type KeGroup = p256::NistP256;
     type KeyExchange = opaque_ke::key_exchange::tripledh::TripleDh;
-    type Ksf = opaque_ke::ksf::Identity;
+    type Ksf = KeypostKsf;
 }
 
 pub fn rng() -> OsRng {
@@ -35,74 +41,51 @@ pub fn rng() -> OsRng {
 
 pub fn login_start(
     client_password: &str,
-) -> Result<ClientLoginStartResult<DefaultCipherSuite>, ProtocolError> {
+) -> Result<ClientLoginStartResult<DefaultCipherSuite>, CliError> {
     let mut client_rng = OsRng;
     let client_login_start_result =
         ClientLogin::<DefaultCipherSuite>::start(&mut client_rng, client_password.as_bytes())?;
     Ok(client_login_start_result)
 }
 
+// The message finishing the login for the server, the session key and the export key.
+type LoginFinish = (Vec<u8>, Vec<u8>, Vec<u8>);
+
 /// Server sends credential_response_bytes to client
 pub fn login_finish(
     password: String,
     client_login_start_result: ClientLoginStartResult<DefaultCipherSuite>,
     credential_response: &[u8],
-) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>), ProtocolError> {
+    client_identifier: Option<&[u8]>,
+    ksf: &KeypostKsf,
+) -> Result<LoginFinish, CliError> {
     let client_login_finish_result = client_login_start_result.state.finish(
         password.as_bytes(),
-        CredentialResponse::deserialize(credential_response)?,
+        CredentialResponse::deserialize(credential_response).map_err(malformed)?,
         ClientLoginFinishParameters::new(
             None,
             Identifiers {
-                client: None,
+                client: client_identifier,
                 server: None,
             },
-            None,
+            Some(ksf),
         ),
     )?;
-    let export_key = client_login_finish_result.export_key.to_vec();
-    let current_server_static_public_key: Vec<u8> =
-        client_login_finish_result.server_s_pk.serialize().to_vec();
-    match util::read_file("server.public", true) {
-        Ok(registered_server_static_public_key) => {
-            if current_server_static_public_key == registered_server_static_public_key {
-                Ok((
-                    client_login_finish_result.message.serialize().to_vec(),
-                    client_login_finish_result.session_key.to_vec(),
-                    export_key,
-                ))
-            } else {
-                println!("server public key doesn't match, possible man-in-the-middle attack!");
-                Err(ProtocolError::InvalidLoginError)
-            }
-        }
-        Err(err) => {
-            if err.kind() == ErrorKind::NotFound {
-                util::write_to_secure_file(
-                    "server.public",
-                    &current_server_static_public_key,
-                    true,
-                )
-                .expect("Could not write server public key to file");
-                Ok((
-                    client_login_finish_result.message.serialize().to_vec(),
-                    client_login_finish_result.session_key.to_vec(),
-                    export_key,
-                ))
-            } else {
-                Err(ProtocolError::InvalidLoginError)
-            }
-        }
-    }
+    server_key::check_and_pin(&client_login_finish_result.server_s_pk.serialize())?;
+    Ok((
+        client_login_finish_result.message.serialize().to_vec(),
+        client_login_finish_result.session_key.to_vec(),
+        client_login_finish_result.export_key.to_vec(),
+    ))
 }
 
 pub fn register_start(
     rng: &mut OsRng,
     password: String,
-) -> ClientRegistrationStartResult<DefaultCipherSuite> {
+) -> Result<ClientRegistrationStartResult<DefaultCipherSuite>, CliError> {
     let client_registration_start_result =
-        ClientRegistration::<DefaultCipherSuite>::start(rng, password.as_bytes()).unwrap();
-    client_registration_start_result
+        ClientRegistration::<DefaultCipherSuite>::start(rng, password.as_bytes())?;
+    Ok(client_registration_start_result)
 }
 
 pub fn register_finish(
@@ -110,40 +93,36 @@ pub fn register_finish(
     password: String,
     client_registration_start_result: ClientRegistrationStartResult<DefaultCipherSuite>,
     registration_response_base64: &str,
-) -> Result<(Vec<u8>, Vec<u8>), ProtocolError> {
-    let registration_response_bytes =
-        base64::decode(registration_response_base64).expect("Could not perform base64 decode");
-    let client_registration_finish_result = client_registration_start_result
-        .state
-        .finish(
-            client_rng,
-            password.as_bytes(),
-            RegistrationResponse::deserialize(&registration_response_bytes[..])
-                .expect("Could not deserialize RegistrationResponse"),
-            ClientRegistrationFinishParameters::new(
-                Identifiers {
-                    client: None,
-                    server: None,
-                },
-                None,
-            ),
-        )
-        .expect("An error occured during ClientRegistrationFinishResult");
+    client_identifier: Option<&[u8]>,
+    ksf: &KeypostKsf,
+) -> Result<(Vec<u8>, Vec<u8>), CliError> {
+    let registration_response_bytes = base64::decode(registration_response_base64)
+        .map_err(|err| CliError::MalformedResponseError(err.to_string()))?;
+    let client_registration_finish_result = client_registration_start_result.state.finish(
+        client_rng,
+        password.as_bytes(),
+        RegistrationResponse::deserialize(&registration_response_bytes[..]).map_err(malformed)?,
+        ClientRegistrationFinishParameters::new(
+            Identifiers {
+                client: client_identifier,
+                server: None,
+            },
+            Some(ksf),
+        ),
+    )?;
     let client_message_bytes = client_registration_finish_result
         .message
         .serialize()
         .to_vec();
     let export_key = client_registration_finish_result.export_key.to_vec();
-    let server_static_public_key = client_registration_finish_result.server_s_pk.serialize();
-    util::write_to_secure_file("server.public", &server_static_public_key, true)
-        .expect("Could not write server public key to file");
+    server_key::check_and_pin(&client_registration_finish_result.server_s_pk.serialize())?;
     Ok((client_message_bytes, export_key))
 }
 
 pub fn register_locker_start(
     rng: &mut OsRng,
     key: &[u8],
-) -> Result<ClientRegistrationStartResult<DefaultCipherSuite>, ProtocolError> {
+) -> Result<ClientRegistrationStartResult<DefaultCipherSuite>, CliError> {
     let client_registration_start_result =
         ClientRegistration::<DefaultCipherSuite>::start(rng, key)?;
     Ok(client_registration_start_result)
@@ -154,19 +133,19 @@ pub fn register_locker_finish(
     client_registration_start_result: ClientRegistrationStartResult<DefaultCipherSuite>,
     registration_response_bytes: &[u8],
     key: &[u8],
-) -> Result<ClientRegistrationFinishResult<DefaultCipherSuite>, ProtocolError> {
-    client_registration_start_result.state.finish(
+) -> Result<ClientRegistrationFinishResult<DefaultCipherSuite>, CliError> {
+    Ok(client_registration_start_result.state.finish(
         rng,
         key,
-        RegistrationResponse::deserialize(registration_response_bytes).unwrap(),
+        RegistrationResponse::deserialize(registration_response_bytes).map_err(malformed)?,
         ClientRegistrationFinishParameters::default(),
-    )
+    )?)
 }
 
 pub fn open_locker_start(
     rng: &mut OsRng,
     key: &[u8],
-) -> Result<ClientLoginStartResult<DefaultCipherSuite>, ProtocolError> {
+) -> Result<ClientLoginStartResult<DefaultCipherSuite>, CliError> {
     let client_login_start_result = ClientLogin::<DefaultCipherSuite>::start(rng, key)?;
     Ok(client_login_start_result)
 }
@@ -175,13 +154,95 @@ pub fn open_locker_finish(
     client_login_start_result: ClientLoginStartResult<DefaultCipherSuite>,
     key: &[u8],
     credential_response: &[u8],
-) -> Result<ClientLoginFinishResult<DefaultCipherSuite>, ProtocolError> {
+) -> Result<ClientLoginFinishResult<DefaultCipherSuite>, CliError> {
     let client_login_finish_result: ClientLoginFinishResult<DefaultCipherSuite> =
         client_login_start_result.state.finish(
             key,
-            CredentialResponse::deserialize(credential_response)
-                .expect("Could not deserialize credential_response"),
+            CredentialResponse::deserialize(credential_response).map_err(malformed)?,
             ClientLoginFinishParameters::default(),
         )?;
     Ok(client_login_finish_result)
 }
+
+// A server message that could not be deserialized, as opposed to a failed protocol step.
+fn malformed(err: ProtocolError) -> CliError {
+    CliError::MalformedResponseError(format!("{:?}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opaque_ke::{
+        CredentialFinalization, CredentialRequest, RegistrationRequest, RegistrationUpload,
+        ServerLogin, ServerLoginStartParameters, ServerRegistration, ServerSetup,
+    };
+
+    const CREDENTIAL_ID: &[u8] = b"locker";
+
+    // The export key from registration, the login result and the server's session key.
+    type RoundTrip = (
+        Vec<u8>,
+        ClientLoginFinishResult<DefaultCipherSuite>,
+        Vec<u8>,
+    );
+
+    // Registers `key` with an in-process server, then logs in with `login_key`, passing every
+    // message through its serialized form as the real exchange does. Run with the ristretto255
+    // feature to cover that suite.
+    fn round_trip(key: &[u8], login_key: &[u8]) -> Result<RoundTrip, CliError> {
+        let mut rng = rng();
+        let server_setup = ServerSetup::<DefaultCipherSuite>::new(&mut rng);
+
+        let registration_start = register_locker_start(&mut rng, key)?;
+        let registration_response = ServerRegistration::start(
+            &server_setup,
+            RegistrationRequest::deserialize(&registration_start.message.serialize())?,
+            CREDENTIAL_ID,
+        )?;
+        let registration_finish = register_locker_finish(
+            &mut rng,
+            registration_start,
+            &registration_response.message.serialize(),
+            key,
+        )?;
+        let password_file = ServerRegistration::finish(RegistrationUpload::deserialize(
+            &registration_finish.message.serialize(),
+        )?);
+
+        let login_start = open_locker_start(&mut rng, login_key)?;
+        let server_login = ServerLogin::start(
+            &mut rng,
+            &server_setup,
+            Some(password_file),
+            CredentialRequest::deserialize(&login_start.message.serialize())?,
+            CREDENTIAL_ID,
+            ServerLoginStartParameters::default(),
+        )?;
+        let login_finish =
+            open_locker_finish(login_start, login_key, &server_login.message.serialize())?;
+        let server_session_key = server_login
+            .state
+            .finish(CredentialFinalization::deserialize(
+                &login_finish.message.serialize(),
+            )?)?
+            .session_key;
+        Ok((
+            registration_finish.export_key.to_vec(),
+            login_finish,
+            server_session_key.to_vec(),
+        ))
+    }
+
+    #[test]
+    fn registration_and_login_round_trip() {
+        let (export_key, login, server_session_key) =
+            round_trip(b"locker key", b"locker key").unwrap();
+        assert_eq!(login.export_key.to_vec(), export_key);
+        assert_eq!(login.session_key.to_vec(), server_session_key);
+    }
+
+    #[test]
+    fn login_with_the_wrong_key_fails() {
+        assert!(round_trip(b"locker key", b"another key").is_err());
+    }
+}
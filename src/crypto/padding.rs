@@ -0,0 +1,195 @@
+use serde::{Deserialize, Serialize};
+
+use crate::models::CliError;
+
+// Length-hiding padding applied to locker plaintexts before encryption. Padded plaintexts are
+// a big-endian u32 length, the plaintext and then zeros up to the padded size.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Padding {
+    #[default]
+    None,
+    Padme,   // PADMÉ: at most ~12% overhead, leaks O(log log n) bits of the length
+    Buckets, // next power of two, at least MIN_BUCKET bytes
+}
+
+const LENGTH_PREFIX_LEN: usize = 4;
+const MIN_BUCKET: usize = 256;
+
+impl Padding {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Padding::None => 0,
+            Padding::Padme => 1,
+            Padding::Buckets => 2,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Result<Padding, CliError> {
+        match byte {
+            0 => Ok(Padding::None),
+            1 => Ok(Padding::Padme),
+            2 => Ok(Padding::Buckets),
+            _ => Err(CliError::MalformedCiphertextError(format!(
+                "unknown padding scheme {}",
+                byte
+            ))),
+        }
+    }
+
+    pub fn pad(self, plaintext: &[u8]) -> Vec<u8> {
+        if self == Padding::None {
+            return plaintext.to_vec();
+        }
+        let len = LENGTH_PREFIX_LEN + plaintext.len();
+        let padded_len = match self {
+            Padding::None => len,
+            Padding::Padme => padme(len),
+            Padding::Buckets => len.max(MIN_BUCKET).next_power_of_two(),
+        };
+        let mut padded = Vec::with_capacity(padded_len);
+        padded.extend_from_slice(&(plaintext.len() as u32).to_be_bytes());
+        padded.extend_from_slice(plaintext);
+        padded.resize(padded_len, 0);
+        padded
+    }
+
+    pub fn unpad(self, padded: &[u8]) -> Result<Vec<u8>, CliError> {
+        if self == Padding::None {
+            return Ok(padded.to_vec());
+        }
+        if padded.len() < LENGTH_PREFIX_LEN {
+            return Err(CliError::MalformedCiphertextError(
+                "padded plaintext too short".to_string(),
+            ));
+        }
+        let mut prefix = [0u8; LENGTH_PREFIX_LEN];
+        prefix.copy_from_slice(&padded[..LENGTH_PREFIX_LEN]);
+        let len = u32::from_be_bytes(prefix) as usize;
+        let rest = &padded[LENGTH_PREFIX_LEN..];
+        if len > rest.len() {
+            return Err(CliError::MalformedCiphertextError(
+                "padding length out of range".to_string(),
+            ));
+        }
+        let (plaintext, zeros) = rest.split_at(len);
+        if zeros.iter().any(|&byte| byte != 0) {
+            return Err(CliError::MalformedCiphertextError(
+                "padding is not all zeros".to_string(),
+            ));
+        }
+        Ok(plaintext.to_vec())
+    }
+}
+
+// PADMÉ (Nikitin et al., "Reducing Metadata Leakage from Encrypted Files and Communication with
+// PURBs"): keep only the top floor(log2(e)) + 1 bits of the length, where e = floor(log2(len)).
+fn padme(len: usize) -> usize {
+    if len < 2 {
+        return len;
+    }
+    let e = usize::BITS - 1 - len.leading_zeros();
+    let s = u32::BITS - e.leading_zeros();
+    let mask = (1usize << (e - s)) - 1;
+    (len + mask) & !mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(padding: Padding, len: usize) -> usize {
+        let plaintext: Vec<u8> = (0..len).map(|i| (i % 251) as u8 + 1).collect();
+        let padded = padding.pad(&plaintext);
+        assert_eq!(
+            padding.unpad(&padded).unwrap(),
+            plaintext,
+            "{:?} {}",
+            padding,
+            len
+        );
+        padded.len()
+    }
+
+    #[test]
+    fn no_padding_leaves_plaintexts_alone() {
+        for len in [0, 1, 255, 256, 257].iter() {
+            assert_eq!(round_trip(Padding::None, *len), *len);
+        }
+    }
+
+    #[test]
+    fn buckets_round_trip_at_their_boundaries() {
+        // The padded length includes the 4-byte length prefix.
+        let cases = [
+            (0, 256),
+            (1, 256),
+            (252, 256),
+            (253, 512),
+            (508, 512),
+            (509, 1024),
+        ];
+        for (len, padded_len) in cases.iter() {
+            assert_eq!(round_trip(Padding::Buckets, *len), *padded_len, "{}", len);
+        }
+    }
+
+    #[test]
+    fn padme_round_trips_at_its_boundaries() {
+        // 256 keeps its length; 257 rounds up to a multiple of 16; 100 to a multiple of 8.
+        let cases = [
+            (0, 4),
+            (1, 5),
+            (96, 104),
+            (252, 256),
+            (253, 272),
+            (268, 272),
+        ];
+        for (len, padded_len) in cases.iter() {
+            assert_eq!(round_trip(Padding::Padme, *len), *padded_len, "{}", len);
+        }
+    }
+
+    #[test]
+    fn padme_overhead_stays_under_twelve_percent() {
+        for len in 1..100_000 {
+            let padded = padme(len);
+            assert!(padded >= len);
+            assert!(padded as f64 <= len as f64 * 1.12, "{} -> {}", len, padded);
+        }
+    }
+
+    #[test]
+    fn malformed_padding_is_an_error() {
+        let mut nonzero = Padding::Buckets.pad(b"secret");
+        *nonzero.last_mut().unwrap() = 1;
+        let malformed: [&[u8]; 5] = [
+            &[],
+            &[0, 0, 1],
+            &[0, 0, 0, 5, 1, 2, 3, 4],
+            &[0xff, 0xff, 0xff, 0xff, 0],
+            &nonzero,
+        ];
+        for padding in [Padding::Padme, Padding::Buckets].iter() {
+            for padded in malformed.iter() {
+                assert!(
+                    matches!(
+                        padding.unpad(padded),
+                        Err(CliError::MalformedCiphertextError(_))
+                    ),
+                    "{:?} {:?}",
+                    padding,
+                    padded
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn padding_schemes_round_trip_through_their_byte() {
+        for padding in [Padding::None, Padding::Padme, Padding::Buckets].iter() {
+            assert_eq!(Padding::from_byte(padding.to_byte()).unwrap(), *padding);
+        }
+        assert!(Padding::from_byte(3).is_err());
+    }
+}
@@ -0,0 +1,273 @@
+use rand::Rng;
+
+use crate::crypto;
+
+const LOWERCASE: &str = "abcdefghijklmnopqrstuvwxyz";
+const UPPERCASE: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+const DIGITS: &str = "0123456789";
+const SYMBOLS: &str = "!#$%&()*+,-./:;<=>?@[]^_{|}~";
+// Characters that are easily confused with one another when read or typed by hand.
+const AMBIGUOUS: &str = "Il1O0o|";
+
+const DEFAULT_PASSWORD_LENGTH: usize = 24;
+const DEFAULT_PASSPHRASE_WORDS: usize = 6;
+const MIN_PASSWORD_LENGTH: usize = 8;
+const MAX_PASSWORD_LENGTH: usize = 1024;
+
+// EFF large wordlist (https://www.eff.org/dice), one "<dice rolls>\t<word>" entry per line.
+const EFF_WORDLIST: &str = include_str!("../assets/eff_large_wordlist.txt");
+const EFF_WORDLIST_LEN: usize = 7776;
+
+pub struct PasswordPolicy {
+    pub length: usize,
+    pub lowercase: bool,
+    pub uppercase: bool,
+    pub digits: bool,
+    pub symbols: bool,
+    pub exclude_ambiguous: bool,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy {
+            length: DEFAULT_PASSWORD_LENGTH,
+            lowercase: true,
+            uppercase: true,
+            digits: true,
+            symbols: true,
+            exclude_ambiguous: false,
+        }
+    }
+}
+
+pub struct PassphrasePolicy {
+    pub words: usize,
+    pub separator: String,
+}
+
+impl Default for PassphrasePolicy {
+    fn default() -> Self {
+        PassphrasePolicy {
+            words: DEFAULT_PASSPHRASE_WORDS,
+            separator: "-".to_string(),
+        }
+    }
+}
+
+pub struct Generated {
+    pub secret: String,
+    pub entropy_bits: f64,
+}
+
+/// Generate a random password where every enabled character class appears at least once.
+pub fn generate_password(policy: &PasswordPolicy) -> Result<Generated, String> {
+    if policy.length < MIN_PASSWORD_LENGTH || policy.length > MAX_PASSWORD_LENGTH {
+        return Err(format!(
+            "Password length must be between {} and {}",
+            MIN_PASSWORD_LENGTH, MAX_PASSWORD_LENGTH
+        ));
+    }
+    let classes = character_classes(policy);
+    if classes.is_empty() {
+        return Err("At least one character class must be enabled".to_string());
+    }
+    if classes.len() > policy.length {
+        return Err("Password length is shorter than the number of character classes".to_string());
+    }
+    let pool: Vec<char> = classes.iter().flatten().copied().collect();
+
+    // Rejection sampling keeps the distribution uniform over all passwords that satisfy the policy.
+    let mut rng = crypto::opaque::rng();
+    let secret = loop {
+        let candidate: Vec<char> = (0..policy.length)
+            .map(|_| pool[rng.gen_range(0..pool.len())])
+            .collect();
+        if classes
+            .iter()
+            .all(|class| candidate.iter().any(|c| class.contains(c)))
+        {
+            break candidate.into_iter().collect::<String>();
+        }
+    };
+    Ok(Generated {
+        secret,
+        entropy_bits: password_entropy(&classes, policy.length),
+    })
+}
+
+/// Generate a diceware passphrase from the embedded EFF large wordlist.
+pub fn generate_passphrase(policy: &PassphrasePolicy) -> Result<Generated, String> {
+    if policy.words == 0 {
+        return Err("Passphrase must contain at least one word".to_string());
+    }
+    let words = eff_wordlist()?;
+    let mut rng = crypto::opaque::rng();
+    let secret = (0..policy.words)
+        .map(|_| words[rng.gen_range(0..words.len())])
+        .collect::<Vec<&str>>()
+        .join(&policy.separator);
+    Ok(Generated {
+        secret,
+        entropy_bits: policy.words as f64 * (words.len() as f64).log2(),
+    })
+}
+
+fn character_classes(policy: &PasswordPolicy) -> Vec<Vec<char>> {
+    [
+        (policy.lowercase, LOWERCASE),
+        (policy.uppercase, UPPERCASE),
+        (policy.digits, DIGITS),
+        (policy.symbols, SYMBOLS),
+    ]
+    .iter()
+    .filter(|(enabled, _)| *enabled)
+    .map(|(_, chars)| {
+        chars
+            .chars()
+            .filter(|c| !(policy.exclude_ambiguous && AMBIGUOUS.contains(*c)))
+            .collect::<Vec<char>>()
+    })
+    .collect()
+}
+
+// log2 of the number of passwords of the given length containing every class, computed by
+// inclusion-exclusion over the classes that are left out.
+fn password_entropy(classes: &[Vec<char>], length: usize) -> f64 {
+    let pool = classes.iter().map(|class| class.len()).sum::<usize>() as f64;
+    let mut fraction = 0.0;
+    for mask in 0..(1u32 << classes.len()) {
+        let excluded: usize = classes
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| mask & (1 << i) != 0)
+            .map(|(_, class)| class.len())
+            .sum();
+        let term = ((pool - excluded as f64) / pool).powi(length as i32);
+        match mask.count_ones() % 2 {
+            0 => fraction += term,
+            _ => fraction -= term,
+        }
+    }
+    length as f64 * pool.log2() + fraction.log2()
+}
+
+pub fn eff_wordlist() -> Result<Vec<&'static str>, String> {
+    let words: Vec<&str> = EFF_WORDLIST
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .collect();
+    if words.len() != EFF_WORDLIST_LEN {
+        return Err(format!(
+            "Embedded EFF wordlist has {} words, expected {}",
+            words.len(),
+            EFF_WORDLIST_LEN
+        ));
+    }
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn passwords_contain_every_enabled_class() {
+        let policy = PasswordPolicy {
+            length: MIN_PASSWORD_LENGTH,
+            ..Default::default()
+        };
+        for _ in 0..200 {
+            let password = generate_password(&policy).unwrap().secret;
+            assert_eq!(password.chars().count(), MIN_PASSWORD_LENGTH);
+            for class in [LOWERCASE, UPPERCASE, DIGITS, SYMBOLS] {
+                assert!(password.chars().any(|c| class.contains(c)), "{}", password);
+            }
+        }
+    }
+
+    #[test]
+    fn passwords_only_use_enabled_unambiguous_characters() {
+        let policy = PasswordPolicy {
+            uppercase: false,
+            symbols: false,
+            exclude_ambiguous: true,
+            ..Default::default()
+        };
+        for _ in 0..200 {
+            let password = generate_password(&policy).unwrap().secret;
+            assert!(password
+                .chars()
+                .all(|c| (LOWERCASE.contains(c) || DIGITS.contains(c)) && !AMBIGUOUS.contains(c)));
+        }
+    }
+
+    #[test]
+    fn refuses_unsatisfiable_password_policies() {
+        let too_short = PasswordPolicy {
+            length: MIN_PASSWORD_LENGTH - 1,
+            ..Default::default()
+        };
+        let too_long = PasswordPolicy {
+            length: MAX_PASSWORD_LENGTH + 1,
+            ..Default::default()
+        };
+        let no_classes = PasswordPolicy {
+            lowercase: false,
+            uppercase: false,
+            digits: false,
+            symbols: false,
+            ..Default::default()
+        };
+        for policy in [too_short, too_long, no_classes].iter() {
+            assert!(generate_password(policy).is_err());
+        }
+    }
+
+    #[test]
+    fn password_entropy_counts_only_passwords_with_every_class() {
+        let lowercase = vec![LOWERCASE.chars().collect::<Vec<char>>()];
+        assert_close(password_entropy(&lowercase, 10), 10.0 * 26f64.log2());
+        // Two characters from lowercase and digits: 36^2 - 26^2 - 10^2 = 520 contain both.
+        let classes = vec![
+            LOWERCASE.chars().collect::<Vec<char>>(),
+            DIGITS.chars().collect::<Vec<char>>(),
+        ];
+        assert_close(password_entropy(&classes, 2), 520f64.log2());
+    }
+
+    #[test]
+    fn passphrases_use_the_wordlist_and_report_its_entropy() {
+        let policy = PassphrasePolicy {
+            words: 6,
+            separator: " ".to_string(),
+        };
+        let words = eff_wordlist().unwrap();
+        let generated = generate_passphrase(&policy).unwrap();
+        let chosen: Vec<&str> = generated.secret.split(' ').collect();
+        assert_eq!(chosen.len(), 6);
+        assert!(chosen.iter().all(|word| words.contains(word)));
+        assert_close(generated.entropy_bits, 6.0 * 7776f64.log2());
+        assert!(generate_passphrase(&PassphrasePolicy {
+            words: 0,
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn embeds_the_full_wordlist() {
+        let words = eff_wordlist().unwrap();
+        assert_eq!(words.len(), EFF_WORDLIST_LEN);
+        assert_eq!(words.first(), Some(&"abacus"));
+        assert_eq!(words.last(), Some(&"zoom"));
+    }
+}
@@ -0,0 +1,282 @@
+use sha1::{Digest, Sha1};
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+
+// Offline lookups against the Have I Been Pwned "Pwned Passwords" SHA-1 dataset.
+// Two formats are supported:
+//  * the downloaded text file, one "<SHA-1 hex>:<count>" line per hash, sorted by hash
+//  * a compact index built from it: COMPACT_MAGIC followed by fixed-size records of the
+//    20-byte hash and a big-endian u32 count
+// Both are binary searched on disk, so the dataset never has to fit in memory. The per-prefix range
+// files fetched by the HIBP downloader (hash suffixes only, one file per 5-character prefix) are
+// not supported.
+
+const COMPACT_MAGIC: &[u8; 8] = b"KPHIBP1\n";
+const HASH_LEN: usize = 20;
+const RECORD_LEN: u64 = HASH_LEN as u64 + 4;
+
+pub enum BreachIndex {
+    Text(BufReader<File>, u64),
+    Compact(File, u64),
+}
+
+pub fn open(path: &str) -> Result<BreachIndex, String> {
+    let mut file = File::open(path).map_err(|err| format!("Could not open {}: {}", path, err))?;
+    let len = file
+        .metadata()
+        .map_err(|err| format!("Could not read {}: {}", path, err))?
+        .len();
+    let mut magic = [0u8; 8];
+    let is_compact =
+        len >= magic.len() as u64 && file.read_exact(&mut magic).is_ok() && &magic == COMPACT_MAGIC;
+    if is_compact {
+        let records = (len - COMPACT_MAGIC.len() as u64) / RECORD_LEN;
+        Ok(BreachIndex::Compact(file, records))
+    } else {
+        Ok(BreachIndex::Text(BufReader::new(file), len))
+    }
+}
+
+impl BreachIndex {
+    /// Returns how many times `secret` appears in the dataset, or None if it was never seen.
+    pub fn lookup(&mut self, secret: &str) -> Result<Option<u32>, String> {
+        let hash: [u8; HASH_LEN] = Sha1::digest(secret.as_bytes()).into();
+        let result = match self {
+            BreachIndex::Text(reader, len) => lookup_text(reader, *len, &hash),
+            BreachIndex::Compact(file, records) => lookup_compact(file, *records, &hash),
+        };
+        result.map_err(|err| format!("Error reading breach dataset: {}", err))
+    }
+}
+
+/// Convert a sorted HIBP text file into the compact index format.
+pub fn build_index(text_path: &str, index_path: &str) -> Result<u64, String> {
+    let input =
+        File::open(text_path).map_err(|err| format!("Could not open {}: {}", text_path, err))?;
+    let output = File::create(index_path)
+        .map_err(|err| format!("Could not create {}: {}", index_path, err))?;
+    let mut writer = BufWriter::new(output);
+    writer
+        .write_all(COMPACT_MAGIC)
+        .map_err(|err| err.to_string())?;
+
+    let mut previous: Option<[u8; HASH_LEN]> = None;
+    let mut records = 0;
+    for (number, line) in BufReader::new(input).lines().enumerate() {
+        let line = line.map_err(|err| format!("Could not read {}: {}", text_path, err))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (hash, count) = parse_line(&line)
+            .ok_or_else(|| format!("Malformed line {} in {}", number + 1, text_path))?;
+        if matches!(previous, Some(previous) if previous >= hash) {
+            return Err(format!(
+                "{} is not sorted by hash (line {})",
+                text_path,
+                number + 1
+            ));
+        }
+        writer.write_all(&hash).map_err(|err| err.to_string())?;
+        writer
+            .write_all(&count.to_be_bytes())
+            .map_err(|err| err.to_string())?;
+        previous = Some(hash);
+        records += 1;
+    }
+    writer.flush().map_err(|err| err.to_string())?;
+    Ok(records)
+}
+
+fn lookup_text(
+    reader: &mut BufReader<File>,
+    len: u64,
+    hash: &[u8; HASH_LEN],
+) -> Result<Option<u32>, std::io::Error> {
+    // Invariant: `lo` is the start of a line, and the matching line (if any) starts in [lo, hi).
+    let (mut lo, mut hi) = (0u64, len);
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        let start = next_line_start(reader, mid, len)?;
+        if start >= hi {
+            hi = mid;
+            continue;
+        }
+        reader.seek(SeekFrom::Start(start))?;
+        let mut line = String::new();
+        let read = reader.read_line(&mut line)? as u64;
+        match parse_line(&line) {
+            Some((line_hash, count)) => match line_hash.cmp(hash) {
+                Ordering::Less => lo = start + read,
+                Ordering::Equal => return Ok(Some(count)),
+                Ordering::Greater => hi = mid,
+            },
+            None => lo = start + read.max(1),
+        }
+    }
+    Ok(None)
+}
+
+// The first line start at or after `pos`.
+fn next_line_start(
+    reader: &mut BufReader<File>,
+    pos: u64,
+    len: u64,
+) -> Result<u64, std::io::Error> {
+    if pos == 0 {
+        return Ok(0);
+    }
+    reader.seek(SeekFrom::Start(pos - 1))?;
+    let mut skipped = Vec::new();
+    let read = reader.read_until(b'\n', &mut skipped)? as u64;
+    Ok((pos - 1 + read).min(len))
+}
+
+fn lookup_compact(
+    file: &mut File,
+    records: u64,
+    hash: &[u8; HASH_LEN],
+) -> Result<Option<u32>, std::io::Error> {
+    let (mut lo, mut hi) = (0u64, records);
+    let mut record = [0u8; RECORD_LEN as usize];
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        file.seek(SeekFrom::Start(
+            COMPACT_MAGIC.len() as u64 + mid * RECORD_LEN,
+        ))?;
+        file.read_exact(&mut record)?;
+        match record[..HASH_LEN].cmp(&hash[..]) {
+            Ordering::Less => lo = mid + 1,
+            Ordering::Equal => {
+                let mut count = [0u8; 4];
+                count.copy_from_slice(&record[HASH_LEN..]);
+                return Ok(Some(u32::from_be_bytes(count)));
+            }
+            Ordering::Greater => hi = mid,
+        }
+    }
+    Ok(None)
+}
+
+fn parse_line(line: &str) -> Option<([u8; HASH_LEN], u32)> {
+    let mut parts = line.trim().splitn(2, ':');
+    let hex = parts.next()?;
+    let count = parts
+        .next()
+        .map_or(Some(0), |count| count.trim().parse::<u64>().ok())?;
+    if hex.len() != HASH_LEN * 2 {
+        return None;
+    }
+    let mut hash = [0u8; HASH_LEN];
+    for (i, byte) in hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some((hash, count.min(u32::MAX as u64) as u32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::{Path, PathBuf};
+
+    const BREACHED: [&str; 5] = ["123456", "password", "qwerty", "letmein", "dragon"];
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("keypost-hibp-{}-{}", std::process::id(), name))
+    }
+
+    fn hex(hash: &[u8]) -> String {
+        hash.iter().map(|byte| format!("{:02X}", byte)).collect()
+    }
+
+    // The fixture in dataset order, each secret counted by its position plus one, with CRLF line
+    // endings like the downloaded file.
+    fn fixture(name: &str) -> (PathBuf, Vec<(&'static str, u32)>) {
+        let mut secrets: Vec<_> = BREACHED
+            .iter()
+            .map(|secret| (*secret, hex(&Sha1::digest(secret.as_bytes()))))
+            .collect();
+        secrets.sort_by(|a, b| a.1.cmp(&b.1));
+        let text: String = secrets
+            .iter()
+            .enumerate()
+            .map(|(i, (_, hash))| format!("{}:{}\r\n", hash, i + 1))
+            .collect();
+        let path = temp_path(name);
+        std::fs::write(&path, text).unwrap();
+        let expected = secrets
+            .iter()
+            .enumerate()
+            .map(|(i, (secret, _))| (*secret, i as u32 + 1))
+            .collect();
+        (path, expected)
+    }
+
+    fn check_lookups(path: &Path, expected: &[(&str, u32)]) {
+        let mut index = open(path.to_str().unwrap()).unwrap();
+        // Includes the first and last lines of the dataset.
+        for (secret, count) in expected {
+            assert_eq!(index.lookup(secret).unwrap(), Some(*count), "{}", secret);
+        }
+        assert_eq!(index.lookup("vT7#qLz9!mWx2@Rk").unwrap(), None);
+        assert_eq!(index.lookup("").unwrap(), None);
+    }
+
+    #[test]
+    fn finds_every_line_of_a_sorted_text_file() {
+        let (path, expected) = fixture("text");
+        check_lookups(&path, &expected);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn a_compact_index_answers_like_its_text_file() {
+        let (text, expected) = fixture("source");
+        let compact = temp_path("compact");
+        let records = build_index(text.to_str().unwrap(), compact.to_str().unwrap()).unwrap();
+        assert_eq!(records, BREACHED.len() as u64);
+        assert!(matches!(
+            open(compact.to_str().unwrap()).unwrap(),
+            BreachIndex::Compact(_, 5)
+        ));
+        check_lookups(&compact, &expected);
+        std::fs::remove_file(text).unwrap();
+        std::fs::remove_file(compact).unwrap();
+    }
+
+    #[test]
+    fn an_empty_dataset_finds_nothing() {
+        let path = temp_path("empty");
+        std::fs::write(&path, "").unwrap();
+        assert_eq!(
+            open(path.to_str().unwrap())
+                .unwrap()
+                .lookup("password")
+                .unwrap(),
+            None
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn refuses_to_index_unsorted_or_malformed_files() {
+        let (text, _) = fixture("unsorted");
+        let mut lines: Vec<_> = std::fs::read_to_string(&text)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect();
+        lines.reverse();
+        std::fs::write(&text, lines.join("\n")).unwrap();
+        let output = temp_path("unsorted.idx");
+        let err = build_index(text.to_str().unwrap(), output.to_str().unwrap()).unwrap_err();
+        assert!(err.contains("is not sorted"), "{}", err);
+
+        // A line from a range file: the hash suffix without its 5-character prefix.
+        std::fs::write(&text, "0018A45C4D1DEF81644B54AB7F969B88D65:1\n").unwrap();
+        let err = build_index(text.to_str().unwrap(), output.to_str().unwrap()).unwrap_err();
+        assert!(err.contains("Malformed line 1"), "{}", err);
+        std::fs::remove_file(text).unwrap();
+        std::fs::remove_file(output).unwrap();
+    }
+}
@@ -1,23 +1,34 @@
+use crate::config;
+use crate::crypto;
 use crate::models::*;
-use reqwest::blocking::Response;
-use reqwest::header::HeaderMap;
+use crate::tls;
+use rand::Rng;
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::{NoProxy, Proxy, StatusCode};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, SystemTime};
 
+// `auth` is set when an existing account re-registers (e.g. to change its key stretching suite).
 pub fn register_start(
-    url: &str,
+    path: &str,
     email: &str,
     input: &str,
     pkce_code_challenge: &str,
+    suite: &str,
+    auth: Option<&str>,
 ) -> Result<RegisterResponse, CliError> {
-    match reqwest::blocking::Client::new()
-        .post(url)
-        .headers(create_headers())
-        .json::<RegisterStartRequest>(&RegisterStartRequest {
-            e: email.to_string(),
-            i: input.to_string(),
-            c: pkce_code_challenge.to_string(),
-        })
-        .send()
-    {
+    match send_idempotent(
+        post(path)?
+            .headers(create_optional_auth_headers(auth))
+            .json::<RegisterStartRequest>(&RegisterStartRequest {
+                e: email.to_string(),
+                i: input.to_string(),
+                c: pkce_code_challenge.to_string(),
+                s: suite.to_string(),
+            }),
+    ) {
         Ok(response) => {
             if response.status().is_success() {
                 response
@@ -32,15 +43,15 @@ pub fn register_start(
 }
 
 pub fn register_finish(
-    url: &str,
+    path: &str,
     id: u32,
     email: &str,
     input: &str,
     pkce_code_verify: &str,
+    auth: Option<&str>,
 ) -> Result<RegisterResponse, CliError> {
-    match reqwest::blocking::Client::new()
-        .post(url)
-        .headers(create_headers())
+    match post(path)?
+        .headers(create_optional_auth_headers(auth))
         .json::<RegisterFinishRequest>(&RegisterFinishRequest {
             id,
             e: email.to_string(),
@@ -63,15 +74,15 @@ pub fn register_finish(
 }
 
 pub fn login_start(email: &str, input: &str) -> Result<LoginResponse, CliError> {
-    match reqwest::blocking::Client::new()
-        .post("http://localhost:8000/login/start")
-        .headers(create_headers())
-        .json::<LoginStartRequest>(&LoginStartRequest {
-            e: email.to_string(),
-            i: input.to_string(),
-        })
-        .send()
-    {
+    match send_idempotent(
+        post("/login/start")?
+            .headers(create_headers())
+            .json::<LoginStartRequest>(&LoginStartRequest {
+                e: email.to_string(),
+                i: input.to_string(),
+                v: crypto::channel::CHANNEL_VERSION,
+            }),
+    ) {
         Ok(response) => {
             if response.status().is_success() {
                 response
@@ -86,8 +97,7 @@ pub fn login_start(email: &str, input: &str) -> Result<LoginResponse, CliError>
 }
 
 pub fn login_finish(id: u32, email: &str, input: &str) -> Result<LoginResponse, CliError> {
-    match reqwest::blocking::Client::new()
-        .post("http://localhost:8000/login/finish")
+    match post("/login/finish")?
         .headers(create_headers())
         .json::<LoginFinishRequest>(&LoginFinishRequest {
             id,
@@ -110,8 +120,7 @@ pub fn login_finish(id: u32, email: &str, input: &str) -> Result<LoginResponse,
 }
 
 pub fn login_verify(id: u32, input: &str) -> Result<LoginResponse, CliError> {
-    match reqwest::blocking::Client::new()
-        .post("http://localhost:8000/login/verify")
+    match post("/login/verify")?
         .headers(create_headers())
         .json::<LoginVerifyRequest>(&LoginVerifyRequest {
             id,
@@ -133,8 +142,7 @@ pub fn login_verify(id: u32, input: &str) -> Result<LoginResponse, CliError> {
 }
 
 pub fn logout(input: &str) -> Result<CliResponse, CliError> {
-    match reqwest::blocking::Client::new()
-        .post("http://localhost:8000/logout")
+    match post("/logout")?
         .headers(create_headers_with_auth(input))
         .send()
     {
@@ -151,22 +159,76 @@ pub fn logout(input: &str) -> Result<CliResponse, CliError> {
     }
 }
 
+pub fn sessions(auth: &str) -> Result<SessionsResponse, CliError> {
+    match send_idempotent(get("/sessions")?.headers(create_headers_with_auth(auth))) {
+        Ok(response) => {
+            if response.status().is_success() {
+                response
+                    .json::<SessionsResponse>()
+                    .map_err(CliError::ApiResponseReqwestError)
+            } else if response.status() == StatusCode::UNAUTHORIZED {
+                Err(CliError::UnauthorizedError)
+            } else {
+                create_error_response::<_>(response)
+            }
+        }
+        Err(err) => Err(CliError::ApiResponseReqwestError(err)),
+    }
+}
+
+pub fn revoke_session(handle: Option<&str>, auth: &str) -> Result<CliResponse, CliError> {
+    match post("/sessions/revoke")?
+        .headers(create_headers_with_auth(auth))
+        .json::<RevokeSessionRequest>(&RevokeSessionRequest {
+            h: handle.map(|handle| handle.to_string()),
+        })
+        .send()
+    {
+        Ok(response) => {
+            if response.status().is_success() {
+                response
+                    .json::<CliResponse>()
+                    .map_err(CliError::ApiResponseReqwestError)
+            } else if response.status() == StatusCode::UNAUTHORIZED {
+                Err(CliError::UnauthorizedError)
+            } else {
+                create_error_response::<_>(response)
+            }
+        }
+        Err(err) => Err(CliError::ApiResponseReqwestError(err)),
+    }
+}
+
+pub fn server_key_rotations() -> Result<ServerKeyRotationsResponse, CliError> {
+    match send_idempotent(get("/server-key/rotations")?.headers(create_headers())) {
+        Ok(response) => {
+            if response.status().is_success() {
+                response
+                    .json::<ServerKeyRotationsResponse>()
+                    .map_err(CliError::ApiResponseReqwestError)
+            } else {
+                create_error_response::<_>(response)
+            }
+        }
+        Err(err) => Err(CliError::ApiResponseReqwestError(err)),
+    }
+}
+
 pub fn register_locker_start(
     id: &str,
-    email: &str,
+    email: Option<&str>,
     input: &str,
     auth: &str,
 ) -> Result<RegisterLockerResponse, CliError> {
-    match reqwest::blocking::Client::new()
-        .post("http://localhost:8000/locker/register/start")
-        .headers(create_headers_with_auth(auth))
-        .json::<RegisterLockerStartRequest>(&RegisterLockerStartRequest {
-            id: id.to_string(),
-            e: email.to_string(),
-            i: input.to_string(),
-        })
-        .send()
-    {
+    match send_idempotent(
+        post("/locker/register/start")?
+            .headers(create_headers_with_auth(auth))
+            .json::<RegisterLockerStartRequest>(&RegisterLockerStartRequest {
+                id: id.to_string(),
+                e: email.map(|email| email.to_string()),
+                i: input.to_string(),
+            }),
+    ) {
         Ok(response) => {
             if response.status().is_success() {
                 response
@@ -184,17 +246,16 @@ pub fn register_locker_start(
 
 pub fn register_locker_finish(
     id: &str,
-    email: &str,
+    email: Option<&str>,
     input: &str,
     ciphertext: &str,
     auth: &str,
 ) -> Result<RegisterLockerResponse, CliError> {
-    match reqwest::blocking::Client::new()
-        .post("http://localhost:8000/locker/register/finish")
+    match post("/locker/register/finish")?
         .headers(create_headers_with_auth(auth))
         .json::<RegisterLockerFinishRequest>(&RegisterLockerFinishRequest {
             id: id.to_string(),
-            e: email.to_string(),
+            e: email.map(|email| email.to_string()),
             i: input.to_string(),
             c: ciphertext.to_string(),
         })
@@ -217,20 +278,20 @@ pub fn register_locker_finish(
 
 pub fn open_locker_start(
     id: &str,
-    email: &str,
+    email: Option<&str>,
     input: &str,
     auth: &str,
 ) -> Result<OpenLockerResponse, CliError> {
-    match reqwest::blocking::Client::new()
-        .post("http://localhost:8000/locker/open/start")
-        .headers(create_headers_with_auth(auth))
-        .json::<OpenLockerStartRequest>(&OpenLockerStartRequest {
-            id: id.to_string(),
-            e: email.to_string(),
-            i: input.to_string(),
-        })
-        .send()
-    {
+    match send_idempotent(
+        post("/locker/open/start")?
+            .headers(create_headers_with_auth(auth))
+            .json::<OpenLockerStartRequest>(&OpenLockerStartRequest {
+                id: id.to_string(),
+                e: email.map(|email| email.to_string()),
+                i: input.to_string(),
+                v: crypto::channel::CHANNEL_VERSION,
+            }),
+    ) {
         Ok(response) => {
             if response.status().is_success() {
                 OpenLockerResponse::new(response)
@@ -246,17 +307,16 @@ pub fn open_locker_start(
 
 pub fn open_locker_finish(
     id: &str,
-    email: &str,
+    email: Option<&str>,
     input: &str,
     nonce: u32,
     auth: &str,
 ) -> Result<OpenLockerResponse, CliError> {
-    match reqwest::blocking::Client::new()
-        .post("http://localhost:8000/locker/open/finish")
+    match post("/locker/open/finish")?
         .headers(create_headers_with_auth(auth))
         .json::<OpenLockerFinishRequest>(&OpenLockerFinishRequest {
             id: id.to_string(),
-            e: email.to_string(),
+            e: email.map(|email| email.to_string()),
             i: input.to_string(),
             n: nonce,
         })
@@ -279,20 +339,19 @@ pub fn open_locker_finish(
 
 pub fn delete_locker_start(
     id: &str,
-    email: &str,
+    email: Option<&str>,
     input: &str,
     auth: &str,
 ) -> Result<DeleteLockerResponse, CliError> {
-    match reqwest::blocking::Client::new()
-        .post("http://localhost:8000/locker/delete/start")
-        .headers(create_headers_with_auth(auth))
-        .json::<DeleteLockerStartRequest>(&DeleteLockerStartRequest {
-            id: id.to_string(),
-            e: email.to_string(),
-            i: input.to_string(),
-        })
-        .send()
-    {
+    match send_idempotent(
+        post("/locker/delete/start")?
+            .headers(create_headers_with_auth(auth))
+            .json::<DeleteLockerStartRequest>(&DeleteLockerStartRequest {
+                id: id.to_string(),
+                e: email.map(|email| email.to_string()),
+                i: input.to_string(),
+            }),
+    ) {
         Ok(response) => {
             if response.status().is_success() {
                 response
@@ -310,17 +369,16 @@ pub fn delete_locker_start(
 
 pub fn delete_locker_finish(
     id: &str,
-    email: &str,
+    email: Option<&str>,
     input: &str,
     nonce: u32,
     auth: &str,
 ) -> Result<DeleteLockerResponse, CliError> {
-    match reqwest::blocking::Client::new()
-        .post("http://localhost:8000/locker/delete/finish")
+    match post("/locker/delete/finish")?
         .headers(create_headers_with_auth(auth))
         .json::<DeleteLockerFinishRequest>(&DeleteLockerFinishRequest {
             id: id.to_string(),
-            e: email.to_string(),
+            e: email.map(|email| email.to_string()),
             i: input.to_string(),
             n: nonce,
         })
@@ -341,6 +399,132 @@ pub fn delete_locker_finish(
     }
 }
 
+fn post(path: &str) -> Result<RequestBuilder, CliError> {
+    let server = server()?;
+    Ok(server.client.post(format!("{}{}", server.base_url, path)))
+}
+
+fn get(path: &str) -> Result<RequestBuilder, CliError> {
+    let server = server()?;
+    Ok(server.client.get(format!("{}{}", server.base_url, path)))
+}
+
+// Built once per process from the profile's config, so requests share pooled connections.
+static SERVER: OnceLock<Server> = OnceLock::new();
+
+struct Server {
+    client: Client,
+    base_url: String,
+    max_retries: u32,
+    retry_base_delay: Duration,
+    max_retry_delay: Duration,
+}
+
+// A client for the profile's server, with its transport security settings applied (see tls.rs).
+fn server() -> Result<&'static Server, CliError> {
+    if let Some(server) = SERVER.get() {
+        return Ok(server);
+    }
+    let config = config::load().map_err(CliError::HttpError)?;
+    let base_url = tls::server_url(&config.server_url, &config.tls)?;
+    let http = &config.http;
+    let builder = tls::client_builder(&config.tls)?
+        .connect_timeout(Duration::from_secs(http.connect_timeout_secs))
+        .timeout(Duration::from_secs(http.timeout_secs));
+    let builder = match &http.proxy.url {
+        Some(url) => builder.proxy(
+            Proxy::all(url.as_str())
+                .map_err(|err| CliError::HttpError(format!("Invalid proxy {}: {}", url, err)))?
+                .no_proxy(
+                    http.proxy
+                        .no_proxy
+                        .as_deref()
+                        .and_then(NoProxy::from_string),
+                ),
+        ),
+        None if http.proxy.from_env => builder,
+        None => builder.no_proxy(),
+    };
+    let server = Server {
+        client: builder.build().map_err(CliError::ApiResponseReqwestError)?,
+        base_url,
+        max_retries: http.max_retries,
+        retry_base_delay: Duration::from_millis(http.retry_base_delay_ms),
+        max_retry_delay: Duration::from_secs(http.max_retry_delay_secs),
+    };
+    Ok(SERVER.get_or_init(|| server))
+}
+
+// Sends a request that is safe to repeat: a start step (the server discards exchanges that are
+// never finished) or a read. Connection failures, timeouts and 429/502/503/504 responses are
+// retried with jittered exponential backoff, or after the server's Retry-After when it sends one.
+// Finish steps consume server state and always go through a plain `send()`.
+fn send_idempotent(request: RequestBuilder) -> Result<Response, reqwest::Error> {
+    let server = match SERVER.get() {
+        Some(server) => server,
+        None => return request.send(),
+    };
+    let mut attempt = 0;
+    loop {
+        let result = match request.try_clone() {
+            Some(request) => request.send(),
+            None => return request.send(),
+        };
+        if attempt >= server.max_retries {
+            return result;
+        }
+        let wait = match &result {
+            Err(err) if err.is_connect() || err.is_timeout() => backoff(server, attempt),
+            Ok(response) if is_retryable(response.status()) => match retry_after(response) {
+                Some(wait) if wait > server.max_retry_delay => return result,
+                Some(wait) => wait,
+                None => backoff(server, attempt),
+            },
+            _ => return result,
+        };
+        attempt += 1;
+        thread::sleep(wait);
+    }
+}
+
+fn is_retryable(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+// Somewhere between half and all of base * 2^attempt, capped at the configured maximum.
+fn backoff(server: &Server, attempt: u32) -> Duration {
+    let delay = server
+        .retry_base_delay
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(server.max_retry_delay);
+    let millis = delay.as_millis() as u64;
+    Duration::from_millis(crypto::opaque::rng().gen_range(millis / 2..=millis))
+}
+
+fn retry_after(response: &Response) -> Option<Duration> {
+    parse_retry_after(
+        response.headers().get(RETRY_AFTER)?.to_str().ok()?,
+        SystemTime::now(),
+    )
+}
+
+// Retry-After is either a number of seconds or an HTTP date; a date in the past means now.
+fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    match value.parse::<u64>() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => httpdate::parse_http_date(value)
+            .ok()
+            .map(|date| date.duration_since(now).unwrap_or_default()),
+    }
+}
+
 fn create_headers() -> HeaderMap {
     let mut headers = HeaderMap::new();
     headers.insert(
@@ -356,7 +540,20 @@ fn create_headers_with_auth(auth: &str) -> HeaderMap {
     headers
 }
 
+fn create_optional_auth_headers(auth: Option<&str>) -> HeaderMap {
+    match auth {
+        Some(auth) => create_headers_with_auth(auth),
+        None => create_headers(),
+    }
+}
+
 fn create_error_response<T>(response: Response) -> Result<T, CliError> {
+    if response.status() == StatusCode::TOO_MANY_REQUESTS {
+        return Err(CliError::RateLimitedError(match retry_after(&response) {
+            Some(wait) => format!("try again in {} seconds", wait.as_secs().max(1)),
+            None => "try again later".to_string(),
+        }));
+    }
     let resp_bytes = response
         .bytes()
         .map_err(CliError::ApiResponseReqwestError)?
@@ -366,3 +563,78 @@ fn create_error_response<T>(response: Response) -> Result<T, CliError> {
     })?;
     Err(CliError::ApiResponseUnknownError(resp_str))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_server(retry_base_delay: Duration, max_retry_delay: Duration) -> Server {
+        Server {
+            client: Client::new(),
+            base_url: "http://localhost:8000".to_string(),
+            max_retries: 3,
+            retry_base_delay,
+            max_retry_delay,
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_with_jitter_up_to_the_maximum() {
+        let server = test_server(Duration::from_millis(100), Duration::from_secs(1));
+        for (attempt, full) in [
+            (0, 100),
+            (1, 200),
+            (2, 400),
+            (3, 800),
+            (4, 1000),
+            (10, 1000),
+        ] {
+            for _ in 0..50 {
+                let wait = backoff(&server, attempt).as_millis();
+                assert!(
+                    (full / 2..=full).contains(&wait),
+                    "attempt {} waited {}ms",
+                    attempt,
+                    wait
+                );
+            }
+        }
+        // No overflow however many attempts are configured.
+        assert!(backoff(&server, u32::MAX) <= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn parses_retry_after_seconds() {
+        let now = SystemTime::now();
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(parse_retry_after(" 0 ", now), Some(Duration::from_secs(0)));
+        assert_eq!(parse_retry_after("-1", now), None);
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[test]
+    fn parses_retry_after_http_dates() {
+        let now = httpdate::parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT").unwrap();
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:51:37 GMT", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:00:00 GMT", now),
+            Some(Duration::from_secs(0))
+        );
+    }
+
+    #[test]
+    fn retries_only_overload_and_gateway_statuses() {
+        for status in [429, 502, 503, 504] {
+            assert!(is_retryable(StatusCode::from_u16(status).unwrap()));
+        }
+        for status in [200, 400, 401, 404, 409, 500, 501] {
+            assert!(!is_retryable(StatusCode::from_u16(status).unwrap()));
+        }
+    }
+}
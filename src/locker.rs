@@ -1,9 +1,37 @@
 use opaque_ke::ClientLoginStartResult;
+use std::io::ErrorKind;
 
+use crate::config;
 use crate::crypto;
 use crate::crypto::DefaultCipherSuite;
 use crate::http;
 use crate::models::*;
+use crate::util;
+
+// "unauthorized" means the session expired before the step that returned it changed anything
+// (opening a locker changes nothing, so that holds for both of its steps). A single-step operation
+// can then simply be run again after logging in; the multi-step ones below (migrations and
+// rewrap) may have completed earlier steps, and pick up from where they stopped when run again. A
+// finish step that registers or deletes a locker is not safe to repeat, so an expired session
+// there is reported with this instead.
+pub const SESSION_EXPIRED_DURING_FINISH: &str =
+    "Your session expired while the server was applying this change, so it was not retried. \
+     Check whether it took effect before running it again.";
+
+// Locker ids are paths such as "prod/db/password"; folders are the leading segments.
+pub fn validate_locker_path(locker_id: &str) -> Result<(), String> {
+    if locker_id.is_empty()
+        || locker_id
+            .split('/')
+            .any(|segment| segment.trim().is_empty())
+    {
+        return Err(format!(
+            "Invalid locker name '{}': path segments must not be empty",
+            locker_id
+        ));
+    }
+    Ok(())
+}
 
 // Password-based registration and encryption of client secret message between a client and server
 pub fn register_locker(
@@ -11,8 +39,60 @@ pub fn register_locker(
     email: &str,
     key: &[u8],
     secret_message: String,
+    tags: &[String],
     auth: &str,
 ) -> Result<String, String> {
+    store_locker(
+        locker_id,
+        email,
+        key,
+        secret_message,
+        tags,
+        util::now(),
+        auth,
+    )
+}
+
+// Register a locker whose contents haven't changed, only how or where they are stored. It keeps
+// the time it was last updated, so rewrapping or migrating doesn't make secrets look rotated to
+// the stale secret audit.
+fn reregister_locker(
+    locker_id: &str,
+    email: &str,
+    key: &[u8],
+    secret_message: String,
+    tags: &[String],
+    auth: &str,
+) -> Result<String, String> {
+    let updated_at = util::read_locker_index()
+        .map_err(|err| format!("Could not read locker index: {:?}", err))?
+        .into_iter()
+        .find(|entry| entry.id == locker_id)
+        .map_or_else(util::now, |entry| entry.updated_at);
+    store_locker(
+        locker_id,
+        email,
+        key,
+        secret_message,
+        tags,
+        updated_at,
+        auth,
+    )
+}
+
+fn store_locker(
+    locker_id: &str,
+    email: &str,
+    key: &[u8],
+    secret_message: String,
+    tags: &[String],
+    updated_at: u64,
+    auth: &str,
+) -> Result<String, String> {
+    validate_locker_path(locker_id)?;
+    let server_id = crypto::blind_locker_id(key, locker_id);
+    let config = config::load()?;
+    let request_email = config.privacy.locker_email(email);
     let mut client_rng = crypto::opaque::rng();
     let client_registration_start_result = crypto::register_locker_start(&mut client_rng, key)
         .map_err(|err| format!("Error from crypto::register_locker_start: {:?}", err))?;
@@ -23,8 +103,8 @@ pub fn register_locker(
 
     // Client sends registration_request_bytes to server
     let registration_response = http::register_locker_start(
-        locker_id,
-        email,
+        &server_id,
+        request_email,
         &base64::encode(registration_request_bytes),
         auth,
     )
@@ -32,8 +112,8 @@ pub fn register_locker(
     if registration_response.o == "unauthorized" {
         return Err("unauthorized".to_string());
     }
-    let registration_response_bytes =
-        base64::decode(registration_response.o).expect("Could not decode base64 str");
+    let registration_response_bytes = base64::decode(registration_response.o)
+        .map_err(|err| CliError::MalformedResponseError(err.to_string()).to_string())?;
 
     // Server sends registration_response_bytes to client
     let client_finish_registration_result = crypto::opaque::register_locker_finish(
@@ -42,38 +122,76 @@ pub fn register_locker(
         &registration_response_bytes,
         key,
     )
-    .map_err(|err| {
-        format!(
-            "Error with ClientRegistrationStartResult finish state: {:?}",
-            err
-        )
-    })?;
+    .map_err(|err| err.to_string())?;
     let message_bytes = client_finish_registration_result.message.serialize();
 
-    // Client encrypts secret message using export key
+    // Client encrypts secret message using export key, bound to the locker and account identity
     let ciphertext = crypto::encrypt_locker(
         &client_finish_registration_result.export_key,
+        locker_id,
+        email,
         secret_message.as_bytes(),
-    );
+        config.cipher,
+        config.padding,
+    )
+    .map_err(|err| err.to_string())?;
 
     let response = http::register_locker_finish(
-        locker_id,
-        email,
+        &server_id,
+        request_email,
         &base64::encode(message_bytes),
         &base64::encode(ciphertext),
         auth,
     )
     .map_err(|err| format!("Error with RegisterLockerResponse: {:?}", err))?;
     if response.o == "unauthorized" {
-        return Err("unauthorized".to_string());
+        return Err(SESSION_EXPIRED_DURING_FINISH.to_string());
     }
+    util::add_to_locker_index(locker_id, tags, updated_at)
+        .map_err(|err| format!("Locker saved, but could not update locker index: {:?}", err))?;
 
     Ok(response.o)
 }
 
 // Open the contents of a locker with a password between a client and server
-//FIXME Not handling locker contents NotFound from server.
 pub fn open_locker(locker_id: &str, email: &str, key: &[u8], auth: &str) -> Result<String, String> {
+    let server_id = crypto::blind_locker_id(key, locker_id);
+    open_locker_by_server_id(locker_id, &server_id, email, key, auth, false)
+}
+
+// Like open_locker, but also reads the legacy ciphertext format (with a warning). Only for lockers
+// that are about to be written again in the current format.
+fn open_locker_to_rewrite(
+    locker_id: &str,
+    email: &str,
+    key: &[u8],
+    auth: &str,
+) -> Result<String, String> {
+    let server_id = crypto::blind_locker_id(key, locker_id);
+    open_locker_by_server_id(locker_id, &server_id, email, key, auth, true)
+}
+
+// Lockers registered before ids were blinded are stored under their plaintext name. They are only
+// opened to be migrated, so the legacy ciphertext format is accepted.
+pub fn open_legacy_locker(
+    locker_id: &str,
+    email: &str,
+    key: &[u8],
+    auth: &str,
+) -> Result<String, String> {
+    open_locker_by_server_id(locker_id, locker_id, email, key, auth, true)
+}
+
+//FIXME Not handling locker contents NotFound from server.
+fn open_locker_by_server_id(
+    locker_id: &str,
+    server_id: &str,
+    email: &str,
+    key: &[u8],
+    auth: &str,
+    accept_legacy: bool,
+) -> Result<String, String> {
+    let request_email = config::locker_email(email)?;
     let mut client_rng = crypto::opaque::rng();
     let client_login_start_result = crypto::opaque::open_locker_start(&mut client_rng, key)
         .map_err(|err| format!("Error from opaque::open_locker_start: {:?}", err))?;
@@ -82,8 +200,8 @@ pub fn open_locker(locker_id: &str, email: &str, key: &[u8], auth: &str) -> Resu
     // Client sends credential_request_bytes to server
 
     let credential_response = http::open_locker_start(
-        locker_id,
-        email,
+        server_id,
+        request_email,
         &base64::encode(credential_request_bytes),
         auth,
     )
@@ -92,26 +210,29 @@ pub fn open_locker(locker_id: &str, email: &str, key: &[u8], auth: &str) -> Resu
     if credential_response.o == "unauthorized" {
         return Err("unauthorized".to_string());
     }
+    check_channel_version(credential_response.v)?;
     let nonce: u32 = credential_response.n;
 
     // Server sends credential_response_bytes to client
 
-    let result = crypto::opaque::open_locker_finish(
+    let credential_response_bytes = base64::decode(&credential_response.o)
+        .map_err(|err| CliError::MalformedResponseError(err.to_string()).to_string())?;
+    let client_login_finish_result = match crypto::opaque::open_locker_finish(
         client_login_start_result,
         key,
-        &base64::decode(credential_response.o).map_err(|_| "Could not base64 decode!")?,
-    );
-    if result.is_err() {
-        return Err(String::from("Incorrect password, please try again."));
-    }
-    let client_login_finish_result = result.unwrap();
+        &credential_response_bytes,
+    ) {
+        Ok(result) => result,
+        Err(err @ CliError::MalformedResponseError(_)) => return Err(err.to_string()),
+        Err(_) => return Err(String::from("Incorrect password, please try again.")),
+    };
     let credential_finalization_bytes = client_login_finish_result.message.serialize();
 
     // Client sends credential_finalization_bytes to server
 
     let encrypted_locker_contents = http::open_locker_finish(
-        locker_id,
-        email,
+        server_id,
+        request_email,
         &base64::encode(credential_finalization_bytes),
         nonce,
         auth,
@@ -120,23 +241,90 @@ pub fn open_locker(locker_id: &str, email: &str, key: &[u8], auth: &str) -> Resu
     if encrypted_locker_contents.o == "unauthorized" {
         return Err("unauthorized".to_string());
     }
-    // Client decrypts contents of locker, first under the session key, and then
-    let plaintext = crypto::decrypt_locker(
-        &client_login_finish_result.export_key,
-        &crypto::decrypt_locker(
+    // Client decrypts contents of locker, first under the session key, and then under the export
+    // key, which fails if the server returned a ciphertext belonging to another locker or account
+    let session_ciphertext = base64::decode(encrypted_locker_contents.o)
+        .map_err(|err| CliError::MalformedResponseError(err.to_string()).to_string())?;
+    let locker_ciphertext = match credential_response.v {
+        crypto::channel::CHANNEL_VERSION => crypto::SecureChannel::client(
             &client_login_finish_result.session_key,
-            &base64::decode(encrypted_locker_contents.o).expect("Could not base64 decode!"),
-        ),
-    );
+            server_id.as_bytes(),
+        )
+        .open(&session_ciphertext)
+        .map_err(|err| err.to_string())?,
+        _ => crypto::decrypt_bytes(&client_login_finish_result.session_key, &session_ciphertext)
+            .map_err(|err| err.to_string())?,
+    };
+    let export_key = &client_login_finish_result.export_key;
+    let plaintext =
+        match crypto::decrypt_locker(export_key, locker_id, email, &locker_ciphertext) {
+            Err(CliError::LegacyCiphertextError) if accept_legacy => {
+                eprintln!(
+                "Warning: locker '{}' uses the legacy format, which is not bound to its name or \
+                 account. It is being rewritten in the current format.",
+                locker_id
+            );
+                crypto::decrypt_bytes(export_key, &locker_ciphertext)
+            }
+            result => result,
+        }
+        .map_err(|err| err.to_string())?;
     String::from_utf8(plaintext).map_err(|_| String::from("UTF8 error"))
 }
 
+// Refuse a locker exchange that negotiates a weaker secure channel than this session's login did,
+// so that a tampered response cannot downgrade it to the legacy scheme.
+fn check_channel_version(version: u32) -> Result<(), String> {
+    let negotiated = util::read_channel_version()
+        .map_err(|err| format!("Could not read channel version: {:?}", err))?;
+    if version < negotiated {
+        return Err(format!(
+            "Server downgraded the secure channel from version {} to {}, refusing to continue",
+            negotiated, version
+        ));
+    }
+    if version > crypto::channel::CHANNEL_VERSION {
+        return Err(format!(
+            "Server chose unsupported secure channel version {}",
+            version
+        ));
+    }
+    Ok(())
+}
+
 pub fn delete_locker(
     locker_id: &str,
     email: &str,
     key: &[u8],
     auth: &str,
 ) -> Result<String, String> {
+    let response =
+        delete_locker_by_server_id(&crypto::blind_locker_id(key, locker_id), email, key, auth)?;
+    util::remove_from_locker_index(locker_id).map_err(|err| {
+        format!(
+            "Locker deleted, but could not update locker index: {:?}",
+            err
+        )
+    })?;
+    Ok(response)
+}
+
+pub fn delete_legacy_locker(
+    locker_id: &str,
+    email: &str,
+    key: &[u8],
+    auth: &str,
+) -> Result<String, String> {
+    delete_locker_by_server_id(locker_id, email, key, auth)
+}
+
+fn delete_locker_by_server_id(
+    server_id: &str,
+    email: &str,
+    key: &[u8],
+    auth: &str,
+) -> Result<String, String> {
+    let request_email = config::locker_email(email)?;
     // Flow to prove ownership is same as open_locker()
     let mut client_rng = crypto::opaque::rng();
     let start_result: ClientLoginStartResult<DefaultCipherSuite> =
@@ -146,8 +334,8 @@ pub fn delete_locker(
 
     // Client sends credential_request_bytes to server and receives credential response back.
     let response: DeleteLockerResponse = http::delete_locker_start(
-        locker_id,
-        email,
+        server_id,
+        request_email,
         &base64::encode(credential_request_bytes),
         auth,
     )
@@ -156,21 +344,188 @@ pub fn delete_locker(
         return Err("unauthorized".to_string());
     }
     let nonce: u32 = response.n;
-    let response_output: Vec<u8> =
-        base64::decode(response.o).map_err(|err| format!("Could not base64 decode: {:?}", err))?;
+    let response_output: Vec<u8> = base64::decode(response.o)
+        .map_err(|err| CliError::MalformedResponseError(err.to_string()).to_string())?;
 
     let finish_result: opaque_ke::ClientLoginFinishResult<DefaultCipherSuite> =
-        crypto::opaque::open_locker_finish(start_result, key, &response_output)
-            .map_err(|err| format!("Incorrect password, please try again. {:?}", err))?;
+        match crypto::opaque::open_locker_finish(start_result, key, &response_output) {
+            Ok(result) => result,
+            Err(err @ CliError::MalformedResponseError(_)) => return Err(err.to_string()),
+            Err(_) => return Err(String::from("Incorrect password, please try again.")),
+        };
     let finish_message = base64::encode(finish_result.message.serialize());
 
     // Client sends finalized message to server which proves ownership to delete.
     let delete_locker_response: DeleteLockerResponse =
-        http::delete_locker_finish(locker_id, email, &finish_message, nonce, auth)
+        http::delete_locker_finish(server_id, request_email, &finish_message, nonce, auth)
             .map_err(|err| format!("Error from http::delete_locker_finish: {:?}", err))?;
     if delete_locker_response.o == "unauthorized" {
-        return Err("unauthorized".to_string());
+        return Err(SESSION_EXPIRED_DURING_FINISH.to_string());
     }
 
     Ok(delete_locker_response.o)
 }
+
+// Move a locker stored under its plaintext name to its blinded id: open it, register the same
+// contents under the blinded id and only then delete the original. Safe to run again if it stopped
+// part way. Returns false if the locker had already been moved.
+pub fn migrate_legacy_locker(
+    locker_id: &str,
+    email: &str,
+    key: &[u8],
+    tags: &[String],
+    auth: &str,
+) -> Result<bool, String> {
+    let secret_message = match open_legacy_locker(locker_id, email, key, auth) {
+        Ok(secret_message) => secret_message,
+        // Nothing readable under the plaintext name: if the blinded locker opens, it was moved
+        // before the index recorded it.
+        Err(err) => {
+            return match open_locker_to_rewrite(locker_id, email, key, auth) {
+                Ok(_) => util::mark_blinded_in_locker_index(locker_id)
+                    .map(|()| false)
+                    .map_err(|err| format!("Could not update locker index: {:?}", err)),
+                Err(_) => Err(err),
+            }
+        }
+    };
+    if let Err(err) = reregister_locker(locker_id, email, key, secret_message.clone(), tags, auth) {
+        // A previous run may have registered it but stopped before deleting the original. Only
+        // then does the locker under the blinded id hold the same contents; otherwise it is a
+        // different locker created since, and the original must be kept.
+        check_same_contents(
+            open_locker(locker_id, email, key, auth),
+            &secret_message,
+            err,
+        )?;
+    }
+    delete_legacy_locker(locker_id, email, key, auth)?;
+    Ok(true)
+}
+
+// Re-encrypt a locker under the profile's current cipher and padding. The server has no way to
+// replace a locker's contents, so it is deleted and registered again; the plaintext is kept in a
+// recovery file, encrypted under the account's export key, until the new locker is in place.
+pub fn rewrap_locker(
+    locker_id: &str,
+    email: &str,
+    key: &[u8],
+    tags: &[String],
+    auth: &str,
+) -> Result<(), String> {
+    let server_id = crypto::blind_locker_id(key, locker_id);
+    let recovery_file = format!("rewrap-{}.private", server_id);
+    let secret_message = match util::read_file(&recovery_file, true) {
+        Ok(recovered) => {
+            let secret_message = crypto::decrypt_locker(key, locker_id, email, &recovered)
+                .map_err(|err| format!("Could not read {}: {}", recovery_file, err))
+                .and_then(|plaintext| {
+                    String::from_utf8(plaintext).map_err(|_| String::from("UTF8 error"))
+                })?;
+            // A previous run stopped after writing the recovery file, either before deleting the
+            // locker (e.g. the session expired), or after deleting it, or after registering it
+            // again. If it still exists with the saved contents, delete it and carry on.
+            match open_locker_to_rewrite(locker_id, email, key, auth) {
+                Ok(current) if current == secret_message => {
+                    delete_locker_by_server_id(&server_id, email, key, auth)?;
+                }
+                Ok(_) => {
+                    return Err(format!(
+                        "The locker no longer matches {}, refusing to replace it",
+                        recovery_file
+                    ))
+                }
+                Err(err) if err == "unauthorized" => return Err(err),
+                Err(_) => (), // already deleted
+            }
+            secret_message
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            let secret_message = open_locker_to_rewrite(locker_id, email, key, auth)?;
+            let recovery = crypto::encrypt_locker(
+                key,
+                locker_id,
+                email,
+                secret_message.as_bytes(),
+                crypto::Cipher::default(),
+                crypto::Padding::None,
+            )
+            .map_err(|err| err.to_string())?;
+            util::write_to_secure_file(&recovery_file, &recovery, true)
+                .map_err(|err| format!("Could not write {}: {:?}", recovery_file, err))?;
+            // Leaves the index entry (and its updated_at) in place for the new locker.
+            delete_locker_by_server_id(&server_id, email, key, auth)?;
+            secret_message
+        }
+        Err(err) => return Err(format!("Could not read {}: {:?}", recovery_file, err)),
+    };
+    reregister_locker(locker_id, email, key, secret_message, tags, auth)?;
+    util::delete_file(&recovery_file)
+        .map_err(|err| format!("Could not remove {}: {:?}", recovery_file, err))
+}
+
+// Move a locker from one export key to another (after the account's export key changed): register
+// the contents under the new key and only then delete the copy under the old key. A locker that no
+// longer opens under the old key but does under the new one has already been moved.
+pub fn move_locker(
+    locker_id: &str,
+    email: &str,
+    old_key: &[u8],
+    new_key: &[u8],
+    tags: &[String],
+    auth: &str,
+) -> Result<(), String> {
+    // With the same key, "moving" would delete the only copy.
+    if old_key == new_key {
+        return Err("The old and new export keys are the same, refusing to move".to_string());
+    }
+    let secret_message = match open_locker_to_rewrite(locker_id, email, old_key, auth) {
+        Ok(secret_message) => secret_message,
+        Err(err) => {
+            return match open_locker(locker_id, email, new_key, auth) {
+                Ok(_) => Ok(()),
+                Err(_) => Err(err),
+            }
+        }
+    };
+    if let Err(err) = reregister_locker(
+        locker_id,
+        email,
+        new_key,
+        secret_message.clone(),
+        tags,
+        auth,
+    ) {
+        // A previous run may have registered it but stopped before deleting the old copy.
+        check_same_contents(
+            open_locker(locker_id, email, new_key, auth),
+            &secret_message,
+            err,
+        )?;
+    }
+    delete_locker_by_server_id(
+        &crypto::blind_locker_id(old_key, locker_id),
+        email,
+        old_key,
+        auth,
+    )?;
+    Ok(())
+}
+
+// After registering a copy of a locker failed: succeed only if the locker that is already there
+// opens to the same contents, so that deleting the original loses nothing. Otherwise return the
+// registration error.
+fn check_same_contents(
+    existing: Result<String, String>,
+    secret_message: &str,
+    register_err: String,
+) -> Result<(), String> {
+    match existing {
+        Ok(existing) if existing == secret_message => Ok(()),
+        Ok(_) => Err(format!(
+            "{} (a different locker already exists under this name, so the original was kept)",
+            register_err
+        )),
+        Err(_) => Err(register_err),
+    }
+}
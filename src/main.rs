@@ -1,17 +1,30 @@
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
+use std::collections::BTreeMap;
 use std::io::{Error, ErrorKind};
 use std::process::exit;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 mod account;
+mod audit;
+mod config;
 mod crypto;
+mod generator;
+mod hibp;
 mod http;
 mod locker;
 mod models;
+mod server_key;
+mod strength;
+mod tls;
 mod util;
 
+use models::{Secret, SecretType};
+
 const ERROR_EXIT_CODE: i32 = 1;
 
+static COMMAND_FAILED: AtomicBool = AtomicBool::new(false);
+
 const MENU: &str = "
 Choose an option:
 1) Register
@@ -22,14 +35,144 @@ Choose an option:
 6) Logout
 ";
 
-fn init() {
-    util::create_default_directory().expect("Cannot create default directory!");
+fn init(state_dir: Option<&str>) {
+    util::dirs::init(state_dir)
+        .unwrap_or_else(|err| exit_with_error(&format!("Cannot use the state directory: {}", err)));
+    util::state::migrate()
+        .and_then(|()| util::add_missing_checksums())
+        .unwrap_or_else(|err| exit_with_error(&format!("Cannot migrate profile state: {}", err)));
 }
 
 fn main() -> Result<(), Error> {
-    init();
-    //TODO Check for --interactive flag and choose correct run function (needs run_commands() fn).
-    run_interactive()
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let state_dir = take_flag_value(&mut args, "--state-dir");
+    init(state_dir.as_deref());
+    match args.first().map(|arg| arg.as_str()) {
+        None | Some("--interactive") => run_interactive(),
+        Some(_) => run_commands(&args),
+    }
+}
+
+fn run_commands(args: &[String]) -> Result<(), Error> {
+    let mut rl = rustyline::Editor::<()>::new();
+    match args[0].as_str() {
+        "generate" => match generate_secret(&args[1..]) {
+            Ok(generated) => {
+                print_response(&generated.secret);
+                print_response(&format!("Entropy: {:.1} bits", generated.entropy_bits));
+            }
+            Err(err) => exit_with_error(&err),
+        },
+        "put" => {
+            let key_name = get_name_arg(args, &mut rl);
+            let kind = match flag_value(args, "--type") {
+                Some(kind) => SecretType::parse(kind).unwrap_or_else(|err| exit_with_error(&err)),
+                None => SecretType::Note,
+            };
+            let mut fields = BTreeMap::new();
+            for spec in kind.fields() {
+                let value = match spec.name == kind.primary_field() && has_flag(args, "--generate")
+                {
+                    true => match generate_secret(&args[1..]) {
+                        Ok(generated) => {
+                            print_response(&format!(
+                                "Generated {} ({:.1} bits of entropy)",
+                                spec.name, generated.entropy_bits
+                            ));
+                            generated.secret
+                        }
+                        Err(err) => exit_with_error(&err),
+                    },
+                    false => {
+                        let label = match spec.required {
+                            true => spec.name.to_string(),
+                            false => format!("{} (optional)", spec.name),
+                        };
+                        get_string(&label, &mut rl, spec.secret)
+                    }
+                };
+                fields.insert(spec.name.to_string(), value);
+            }
+            let mut meta = BTreeMap::new();
+            for entry in flag_values(args, "--meta") {
+                match entry.split_once('=') {
+                    Some((key, value)) if !key.is_empty() => {
+                        meta.insert(key.to_string(), value.to_string());
+                    }
+                    _ => {
+                        exit_with_error(&format!("Invalid --meta '{}', expected key=value", entry))
+                    }
+                }
+            }
+            let tags = flag_values(args, "--tag")
+                .into_iter()
+                .map(|tag| tag.to_string())
+                .collect();
+            let secret = Secret::new(kind, fields)
+                .and_then(|secret| secret.with_tags(tags))
+                .map(|secret| secret.with_meta(meta))
+                .unwrap_or_else(|err| exit_with_error(&err));
+            execute_put_key_cmd(&mut rl, &key_name, &secret);
+        }
+        "get" => {
+            let field = flag_value(args, "--field");
+            match args.get(1) {
+                Some(name) if !name.starts_with("--") => execute_get_key_cmd(&mut rl, name, field),
+                _ if has_flag(args, "--folder") || has_flag(args, "--tag") => {
+                    for entry in filter_locker_index(args) {
+                        print_response(&format!("== {}", entry.id));
+                        execute_get_key_cmd(&mut rl, &entry.id, field);
+                    }
+                }
+                _ => {
+                    let key_name = get_string("Name", &mut rl, false);
+                    execute_get_key_cmd(&mut rl, &key_name, field);
+                }
+            }
+        }
+        "list" => {
+            for entry in filter_locker_index(args) {
+                match entry.tags.is_empty() {
+                    true => print_response(&entry.id),
+                    false => print_response(&format!("{} [{}]", entry.id, entry.tags.join(", "))),
+                }
+            }
+        }
+        "audit" => match args.get(1).map(|arg| arg.as_str()) {
+            Some("breaches") => execute_audit_breaches_cmd(&mut rl, &args[2..]),
+            Some("hibp-index") => {
+                let (hibp_file, output) = match (
+                    flag_value(args, "--hibp-file"),
+                    flag_value(args, "--output"),
+                ) {
+                    (Some(hibp_file), Some(output)) => (hibp_file, output),
+                    _ => exit_with_error(&format!(
+                        "Usage: keypost audit hibp-index --hibp-file <path> --output <path>\n{}",
+                        HIBP_FILE_HELP
+                    )),
+                };
+                match hibp::build_index(hibp_file, output) {
+                    Ok(records) => print_response(&format!("Indexed {} hashes", records)),
+                    Err(err) => exit_with_error(&err),
+                }
+            }
+            None | Some("--format") => execute_audit_health_cmd(&mut rl, &args[1..]),
+            _ => {
+                exit_with_error("Usage: keypost audit [--format text|json] | breaches | hibp-index")
+            }
+        },
+        "migrate-ids" => execute_migrate_ids_cmd(&mut rl, &args[1..]),
+        "rewrap" => execute_rewrap_cmd(&mut rl, &args[1..]),
+        "migrate-ksf" => execute_migrate_ksf_cmd(&mut rl),
+        "server-key" => execute_server_key_cmd(&mut rl, &args[1..]),
+        "status" => execute_status_cmd(),
+        "sessions" => execute_sessions_cmd(&mut rl, &args[1..]),
+        cmd => exit_with_error(&format!("Unknown command: {}", cmd)),
+    }
+    if COMMAND_FAILED.load(Ordering::Relaxed) {
+        exit(ERROR_EXIT_CODE)
+    }
+    Ok(())
 }
 
 fn run_interactive() -> Result<(), Error> {
@@ -43,6 +186,10 @@ fn run_interactive() -> Result<(), Error> {
                     "1" => {
                         let registration_key = get_string("Registration Key", &mut rl, false);
                         let (email, password) = get_email_password(&mut rl);
+                        if get_string("Confirm Password", &mut rl, true) != password {
+                            print_response("Passwords do not match.");
+                            continue;
+                        }
                         let response = account_registration(registration_key, email, password);
                         print_response(&response);
                     }
@@ -50,56 +197,47 @@ fn run_interactive() -> Result<(), Error> {
                         execute_login_cmd(&mut rl);
                     }
                     "3" => {
-                        //TODO Better way to not use email on server-side? (i.e. server stores email in plaintext)
-                        // Maybe custom identifiers (ClientRegistrationFinishParameters):
-                        //  https://github.com/facebook/opaque-ke/blob/556f6c2bd43123b20110f0a9bace8c5f91643328/src/lib.rs#L706-L722
                         let key_name = get_string("Name", &mut rl, false);
-                        let export_key = util::read_file("export_key.private", true)
-                            .expect("Error reading export_key");
-                        match get_session_file() {
-                            Ok((session_id, email)) => {
-                                match get_key(&email, &key_name, &export_key, &session_id) {
-                                    Ok(response) => print_response(&response),
-                                    Err(error) => handle_error_response(&mut rl, error),
-                                }
-                            }
-                            Err(error) => handle_error_response(&mut rl, error),
-                        }
+                        execute_get_key_cmd(&mut rl, &key_name, None);
                     }
                     "4" => {
                         let key_name = get_string("Name", &mut rl, false);
-                        let message = get_string("Secret", &mut rl, false);
-                        let export_key = util::read_file("export_key.private", true)
-                            .expect("Error reading export_key");
-                        match get_session_file() {
-                            Ok((session_id, email)) => {
-                                match put_key(&email, &key_name, &export_key, message, &session_id)
-                                {
-                                    Ok(response) => print_response(&response),
-                                    Err(error) => handle_error_response(&mut rl, error),
+                        let mut message = get_string("Secret", &mut rl, false);
+                        // The generated secret stays off the screen unless asked for.
+                        let show = message == "--generate --show";
+                        if message == "--generate" || show {
+                            match generator::generate_password(&Default::default()) {
+                                Ok(generated) => {
+                                    print_response(&format!(
+                                        "Generated secret ({:.1} bits of entropy)",
+                                        generated.entropy_bits
+                                    ));
+                                    if show {
+                                        print_response(&generated.secret);
+                                    }
+                                    message = generated.secret;
+                                }
+                                Err(err) => {
+                                    print_response(&err);
+                                    continue;
                                 }
                             }
-                            Err(error) => handle_error_response(&mut rl, error),
                         }
+                        execute_put_key_cmd(&mut rl, &key_name, &Secret::note(message));
                     }
                     "5" => {
                         let key_name = get_string("Name", &mut rl, false);
-                        let export_key = util::read_file("export_key.private", true)
-                            .expect("Error reading export_key");
-                        match get_session_file() {
-                            Ok((session_id, email)) => {
-                                match delete_key(&email, &key_name, &export_key, &session_id) {
-                                    Ok(response) => print_response(&response),
-                                    Err(error) => handle_error_response(&mut rl, error),
-                                }
-                            }
+                        match with_session(&mut rl, |email, export_key, session_id| {
+                            delete_key(email, &key_name, export_key, session_id)
+                        }) {
+                            Ok(response) => print_response(&response),
                             Err(error) => handle_error_response(&mut rl, error),
                         }
                     }
                     //TODO Give option to export all secrets to a file.
                     "6" => match get_session_file() {
                         Ok((session_id, _email)) => {
-                            let _ = util::delete_session_file()
+                            let _ = util::delete_session_file(&session_id)
                                 .map_err(|_err| "Could not delete session file!".to_string());
                             let response = account_logout(&session_id);
                             print_response(&response);
@@ -125,10 +263,551 @@ fn run_interactive() -> Result<(), Error> {
     }
 }
 
+// Locker index entries matching the --folder and --tag filters.
+fn filter_locker_index(args: &[String]) -> Vec<util::LockerIndexEntry> {
+    let folder = flag_value(args, "--folder").unwrap_or("");
+    let tags = flag_values(args, "--tag");
+    read_locker_index()
+        .into_iter()
+        .filter(|entry| entry.in_folder(folder))
+        .filter(|entry| tags.iter().all(|tag| entry.has_tag(tag)))
+        .collect()
+}
+
+fn get_name_arg(args: &[String], rl: &mut Editor<()>) -> String {
+    match args.get(1) {
+        Some(name) if !name.starts_with("--") => name.to_string(),
+        _ => get_string("Name", rl, false),
+    }
+}
+
+fn execute_get_key_cmd(rl: &mut Editor<()>, key_name: &str, field: Option<&str>) {
+    match with_session(rl, |email, export_key, session_id| {
+        get_key(email, key_name, export_key, session_id)
+    }) {
+        Ok(secret) => match field {
+            Some(field) => match secret.field(field) {
+                Ok(value) => print_response(value),
+                Err(err) => print_response(&err),
+            },
+            None => print_response(&secret.display()),
+        },
+        Err(error) => handle_error_response(rl, error),
+    }
+}
+
+fn execute_put_key_cmd(rl: &mut Editor<()>, key_name: &str, secret: &Secret) {
+    match with_session(rl, |email, export_key, session_id| {
+        put_key(email, key_name, export_key, secret, session_id)
+    }) {
+        Ok(response) => print_response(&response),
+        Err(error) => handle_error_response(rl, error),
+    }
+}
+
+fn execute_audit_health_cmd(rl: &mut Editor<()>, args: &[String]) {
+    let json = match flag_value(args, "--format") {
+        None | Some("text") => false,
+        Some("json") => true,
+        Some(format) => exit_with_error(&format!("Unknown format: {}", format)),
+    };
+    let config = config::load().unwrap_or_else(|err| exit_with_error(&err));
+    let lockers = read_locker_index();
+    match with_session(rl, |email, export_key, session_id| {
+        audit::health(&lockers, email, export_key, session_id, &config.audit)
+    }) {
+        Ok(report) => {
+            match json {
+                true => print_response(
+                    &serde_json::to_string_pretty(&report)
+                        .unwrap_or_else(|err| exit_with_error(&err.to_string())),
+                ),
+                false => print_response(&report.to_text()),
+            }
+            if !report.is_healthy() {
+                exit(ERROR_EXIT_CODE)
+            }
+        }
+        Err(error) => handle_error_response(rl, error),
+    }
+}
+
+fn read_locker_index() -> Vec<util::LockerIndexEntry> {
+    util::read_locker_index()
+        .unwrap_or_else(|err| exit_with_error(&format!("Could not read locker index: {:?}", err)))
+}
+
+// Move lockers stored under plaintext names (from before ids were blinded) to blinded ids.
+fn execute_migrate_ids_cmd(rl: &mut Editor<()>, args: &[String]) {
+    let index = read_locker_index();
+    let locker_ids = match positional_args(args, &[]) {
+        names if names.is_empty() => index
+            .iter()
+            .filter(|entry| !entry.blinded)
+            .map(|entry| entry.id.to_string())
+            .collect(),
+        names => names,
+    };
+    for locker_id in locker_ids {
+        let tags = index
+            .iter()
+            .find(|entry| entry.id == locker_id)
+            .map(|entry| entry.tags.clone())
+            .unwrap_or_default();
+        match with_session(rl, |email, export_key, session_id| {
+            locker::migrate_legacy_locker(&locker_id, email, export_key, &tags, session_id)
+        }) {
+            Ok(true) => print_response(&format!("{}: migrated", locker_id)),
+            Ok(false) => print_response(&format!("{}: already migrated", locker_id)),
+            Err(error) if is_session_error(&error) => return handle_error_response(rl, error),
+            Err(error) => {
+                COMMAND_FAILED.store(true, Ordering::Relaxed);
+                print_response(&format!("{}: not migrated ({})", locker_id, error))
+            }
+        }
+    }
+}
+
+// Re-encrypt lockers under the profile's current cipher and padding settings.
+fn execute_rewrap_cmd(rl: &mut Editor<()>, args: &[String]) {
+    let index = read_locker_index();
+    let locker_ids = match positional_args(args, &[]) {
+        names if names.is_empty() => index.iter().map(|entry| entry.id.to_string()).collect(),
+        names => names,
+    };
+    for locker_id in locker_ids {
+        let tags = index
+            .iter()
+            .find(|entry| entry.id == locker_id)
+            .map(|entry| entry.tags.clone())
+            .unwrap_or_default();
+        match with_session(rl, |email, export_key, session_id| {
+            locker::rewrap_locker(&locker_id, email, export_key, &tags, session_id)
+        }) {
+            Ok(()) => print_response(&format!("{}: rewrapped", locker_id)),
+            Err(error) if is_session_error(&error) => return handle_error_response(rl, error),
+            Err(error) => {
+                COMMAND_FAILED.store(true, Ordering::Relaxed);
+                print_response(&format!("{}: not rewrapped ({})", locker_id, error))
+            }
+        }
+    }
+}
+
+// Re-register the account under the configured key stretching suite and move every locker over.
+fn execute_migrate_ksf_cmd(rl: &mut Editor<()>) {
+    let (email, password) = get_email_password(rl);
+    match account::migrate_ksf(email, password) {
+        Ok(results) => {
+            for (locker_id, result) in results {
+                match result {
+                    Ok(()) => print_response(&format!("{}: moved", locker_id)),
+                    Err(error) => {
+                        COMMAND_FAILED.store(true, Ordering::Relaxed);
+                        print_response(&format!("{}: not moved ({})", locker_id, error))
+                    }
+                }
+            }
+            match COMMAND_FAILED.load(Ordering::Relaxed) {
+                true => print_response(
+                    "Some lockers were not moved; run migrate-ksf again to finish the migration.",
+                ),
+                false => print_response("Key stretching migration complete."),
+            }
+        }
+        Err(error) => handle_error_response(rl, error),
+    }
+}
+
+const SERVER_KEY_USAGE: &str =
+    "Usage: keypost server-key show | verify <fingerprint> | trust [--fingerprint <fingerprint>] | history | reset";
+
+// Inspect and manage the pinned server key (see server_key.rs).
+fn execute_server_key_cmd(rl: &mut Editor<()>, args: &[String]) {
+    match args.first().map(|arg| arg.as_str()) {
+        Some("show") => {
+            match or_exit(server_key::pinned()) {
+                Some(key) => print_fingerprint("Pinned server key", &key),
+                None => print_response("No server key is pinned yet."),
+            }
+            if let Some(key) = or_exit(server_key::pending()) {
+                print_fingerprint("Rejected key presented at last login", &key);
+            }
+            if let Some(expected) = or_exit(server_key::read_expected()) {
+                print_response(&format!("The first key pinned must match: {}", expected));
+            }
+        }
+        Some("verify") => {
+            let fingerprint = args
+                .get(1)
+                .map(|_| args[1..].join(" "))
+                .unwrap_or_else(|| exit_with_error(SERVER_KEY_USAGE));
+            let key = or_exit(server_key::pinned())
+                .unwrap_or_else(|| exit_with_error("No server key is pinned yet."));
+            match server_key::matches(&server_key::fingerprint(&key), &fingerprint) {
+                Ok(true) => print_response("The pinned server key matches this fingerprint."),
+                Ok(false) => {
+                    exit_with_error("The pinned server key does NOT match this fingerprint!")
+                }
+                Err(err) => exit_with_error(&err),
+            }
+        }
+        Some("trust") => {
+            let fingerprint = flag_value(args, "--fingerprint");
+            match (or_exit(server_key::pending()), fingerprint) {
+                (Some(key), Some(fingerprint)) => {
+                    match server_key::matches(&server_key::fingerprint(&key), fingerprint) {
+                        Ok(true) => {
+                            or_exit(server_key::pin(&key));
+                            print_response("Pinned the new server key.");
+                        }
+                        Ok(false) => exit_with_error(
+                            "The key presented by the server does NOT match this fingerprint!",
+                        ),
+                        Err(err) => exit_with_error(&err),
+                    }
+                }
+                (Some(key), None) => {
+                    print_fingerprint("Key presented at last login", &key);
+                    let answer = get_string(
+                        "Only trust this key if its fingerprint matches one published by the \
+                         server operator. Trust it? (yes/no)",
+                        rl,
+                        false,
+                    );
+                    match answer.as_str() {
+                        "yes" => {
+                            or_exit(server_key::pin(&key));
+                            print_response("Pinned the new server key.");
+                        }
+                        _ => print_response("Server key not trusted."),
+                    }
+                }
+                (None, Some(fingerprint)) => match or_exit(server_key::pinned()) {
+                    Some(_) => exit_with_error(
+                        "A server key is already pinned; run `keypost server-key reset` first.",
+                    ),
+                    None => {
+                        or_exit(server_key::set_expected(fingerprint));
+                        print_response("The next login will only pin a key with this fingerprint.");
+                    }
+                },
+                (None, None) => exit_with_error(
+                    "No rejected server key to trust. To pre-seed the pin, pass --fingerprint.",
+                ),
+            }
+        }
+        Some("history") => {
+            let history = or_exit(server_key::history());
+            if history.is_empty() {
+                print_response("No server key rotations recorded.");
+            }
+            for record in history {
+                print_response(&format!(
+                    "{} -> {} (signed by {} key, issued {}, accepted {})",
+                    record.old, record.new, record.signer, record.issued_at, record.accepted_at
+                ));
+            }
+        }
+        Some("reset") => {
+            or_exit(server_key::reset());
+            print_response("Server key pin removed; the next login will pin the server's key.");
+        }
+        _ => exit_with_error(SERVER_KEY_USAGE),
+    }
+}
+
+// What this profile is logged in as and where, from local state only.
+fn execute_status_cmd() {
+    match config::load().and_then(|config| {
+        tls::server_url(&config.server_url, &config.tls).map_err(|err| err.to_string())
+    }) {
+        Ok(server_url) => print_response(&format!("Server: {}", server_url)),
+        Err(err) => print_response(&format!("Server: invalid configuration ({})", err)),
+    }
+    match or_exit(server_key::pinned()) {
+        Some(key) => print_fingerprint("Pinned server key", &key),
+        None => print_response("Pinned server key: none yet"),
+    }
+    let session = match util::state::load() {
+        Ok(state) => match state.session {
+            Some(session) => session,
+            None => return print_response("Not logged in."),
+        },
+        Err(err) => exit_with_error(&format!("Could not read profile state: {}", err)),
+    };
+    print_response(&format!("Logged in as: {}", session.email));
+    let now = util::now();
+    match session.logged_in_at {
+        Some(logged_in_at) => print_response(&format!(
+            "Session age: {}",
+            format_duration(now.saturating_sub(logged_in_at))
+        )),
+        None => print_response("Session age: unknown (logged in with an older client)"),
+    }
+    match session.expires_at {
+        Some(expires_at) if expires_at <= now => print_response(&format!(
+            "Session expired {} ago; the next command will ask you to log in again.",
+            format_duration(now - expires_at)
+        )),
+        Some(expires_at) => print_response(&format!(
+            "Session expires in: {}",
+            format_duration(expires_at - now)
+        )),
+        None => print_response("Session expiry: not reported by the server"),
+    }
+}
+
+const SESSIONS_USAGE: &str = "Usage: keypost sessions [list] | revoke <handle> | revoke --all";
+
+// List the account's sessions on the server, or revoke one (by handle) or all of them.
+fn execute_sessions_cmd(rl: &mut Editor<()>, args: &[String]) {
+    match args.first().map(|arg| arg.as_str()) {
+        None | Some("list") => {
+            match with_session(rl, |_email, _export_key, session_id| {
+                account::sessions(session_id)
+            }) {
+                Ok(sessions) => {
+                    let now = util::now();
+                    for session in sessions {
+                        let last_used = match session.last_used_at {
+                            Some(last_used_at) => format!(
+                                "last used {} ago",
+                                format_duration(now.saturating_sub(last_used_at))
+                            ),
+                            None => "never used".to_string(),
+                        };
+                        let expires = match session.expires_at {
+                            Some(expires_at) if expires_at <= now => "expired".to_string(),
+                            Some(expires_at) => {
+                                format!("expires in {}", format_duration(expires_at - now))
+                            }
+                            None => "no expiry".to_string(),
+                        };
+                        print_response(&format!(
+                            "{}{}  created {} ago, {}, {}",
+                            session.h,
+                            if session.current {
+                                " (this session)"
+                            } else {
+                                ""
+                            },
+                            format_duration(now.saturating_sub(session.created_at)),
+                            last_used,
+                            expires
+                        ));
+                    }
+                }
+                Err(error) => handle_error_response(rl, error),
+            }
+        }
+        Some("revoke") => {
+            let handle = match (args.get(1).map(|arg| arg.as_str()), has_flag(args, "--all")) {
+                (_, true) => None,
+                (Some(handle), false) => Some(handle),
+                (None, false) => exit_with_error(SESSIONS_USAGE),
+            };
+            match with_session(rl, |_email, _export_key, session_id| {
+                let current = match handle {
+                    Some(handle) => account::sessions(session_id)?
+                        .iter()
+                        .find(|session| session.h == handle)
+                        .map(|session| session.current)
+                        .ok_or_else(|| format!("No session with handle {}", handle))?,
+                    None => true,
+                };
+                account::revoke_session(session_id, handle, current)
+            }) {
+                Ok(response) => print_response(&response),
+                Err(error) => handle_error_response(rl, error),
+            }
+        }
+        _ => exit_with_error(SESSIONS_USAGE),
+    }
+}
+
+// Whole days, hours or minutes, e.g. "3d 4h", "2h 5m" or "12m".
+fn format_duration(seconds: u64) -> String {
+    let (days, hours, minutes) = (
+        seconds / 86_400,
+        seconds % 86_400 / 3_600,
+        seconds % 3_600 / 60,
+    );
+    match (days, hours) {
+        (0, 0) => format!("{}m", minutes),
+        (0, _) => format!("{}h {}m", hours, minutes),
+        _ => format!("{}d {}h", days, hours),
+    }
+}
+
+fn or_exit<T>(result: Result<T, models::CliError>) -> T {
+    result.unwrap_or_else(|err| exit_with_error(&err.to_string()))
+}
+
+fn print_fingerprint(label: &str, key: &[u8]) {
+    let fingerprint = server_key::fingerprint(key);
+    print_response(&format!(
+        "{}:\n  {}",
+        label,
+        server_key::to_hex(&fingerprint)
+    ));
+    match server_key::to_words(&fingerprint) {
+        Ok(words) => print_response(&format!("  {}", words)),
+        Err(err) => print_response(&format!("  (word fingerprint unavailable: {})", err)),
+    }
+}
+
+// The dataset layouts hibp.rs reads.
+const HIBP_FILE_HELP: &str =
+    "The HIBP file is the single Pwned Passwords SHA-1 file ordered by hash \
+     (\"<hash>:<count>\" lines), or an index built from it with hibp-index. The per-prefix range \
+     files fetched by the HIBP downloader are not supported.";
+
+fn execute_audit_breaches_cmd(rl: &mut Editor<()>, args: &[String]) {
+    let hibp_file = match flag_value(args, "--hibp-file") {
+        Some(hibp_file) => hibp_file.to_string(),
+        None => match config::load().ok().and_then(|config| config.hibp_file) {
+            Some(hibp_file) => hibp_file,
+            None => exit_with_error(&format!(
+                "Usage: keypost audit breaches --hibp-file <path> [name...]\n{}",
+                HIBP_FILE_HELP
+            )),
+        },
+    };
+    let mut index = hibp::open(&hibp_file).unwrap_or_else(|err| exit_with_error(&err));
+    let locker_ids = match positional_args(args, &["--hibp-file"]) {
+        names if names.is_empty() => read_locker_index()
+            .into_iter()
+            .map(|entry| entry.id)
+            .collect(),
+        names => names,
+    };
+    match with_session(rl, |email, export_key, session_id| {
+        audit::breaches(&mut index, &locker_ids, email, export_key, session_id)
+    }) {
+        Ok(report) if report.breached.is_empty() && report.unreadable.is_empty() => print_response(
+            &format!("No breached secrets found in {} lockers.", locker_ids.len()),
+        ),
+        Ok(report) => {
+            for finding in report.breached {
+                print_response(&format!(
+                    "{}: secret has appeared in {} known data breaches",
+                    finding.locker_id, finding.count
+                ));
+            }
+            for finding in report.unreadable {
+                print_response(&format!(
+                    "{}: could not be checked ({})",
+                    finding.locker_id, finding.error
+                ));
+            }
+            exit(ERROR_EXIT_CODE)
+        }
+        Err(error) => handle_error_response(rl, error),
+    }
+}
+
+fn generate_secret(args: &[String]) -> Result<generator::Generated, String> {
+    if has_flag(args, "--passphrase") {
+        let mut policy = generator::PassphrasePolicy::default();
+        if let Some(words) = flag_value(args, "--words") {
+            policy.words = words
+                .parse()
+                .map_err(|_| format!("Invalid number of words: {}", words))?;
+        }
+        if let Some(separator) = flag_value(args, "--separator") {
+            policy.separator = separator.to_string();
+        }
+        generator::generate_passphrase(&policy)
+    } else {
+        let mut policy = generator::PasswordPolicy::default();
+        if let Some(length) = flag_value(args, "--length") {
+            policy.length = length
+                .parse()
+                .map_err(|_| format!("Invalid length: {}", length))?;
+        }
+        policy.lowercase = !has_flag(args, "--no-lowercase");
+        policy.uppercase = !has_flag(args, "--no-uppercase");
+        policy.digits = !has_flag(args, "--no-digits");
+        policy.symbols = !has_flag(args, "--no-symbols");
+        policy.exclude_ambiguous = has_flag(args, "--exclude-ambiguous");
+        generator::generate_password(&policy)
+    }
+}
+
+fn has_flag(args: &[String], flag: &str) -> bool {
+    args.iter().any(|arg| arg == flag)
+}
+
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|arg| arg == flag)
+        .and_then(|i| args.get(i + 1))
+        .map(|value| value.as_str())
+}
+
+fn flag_values<'a>(args: &'a [String], flag: &str) -> Vec<&'a str> {
+    args.iter()
+        .zip(args.iter().skip(1))
+        .filter(|(arg, _)| *arg == flag)
+        .map(|(_, value)| value.as_str())
+        .collect()
+}
+
+// Remove a global flag and its value, which may appear anywhere on the command line.
+fn take_flag_value(args: &mut Vec<String>, flag: &str) -> Option<String> {
+    let i = args.iter().position(|arg| arg == flag)?;
+    if i + 1 >= args.len() {
+        exit_with_error(&format!("{} needs a value", flag))
+    }
+    let value = args.remove(i + 1);
+    args.remove(i);
+    Some(value)
+}
+
+// Arguments that are neither flags nor the values of the given flags.
+fn positional_args(args: &[String], flags_with_values: &[&str]) -> Vec<String> {
+    let mut positional = Vec::new();
+    let mut skip_next = false;
+    for arg in args {
+        if skip_next {
+            skip_next = false;
+        } else if flags_with_values.contains(&arg.as_str()) {
+            skip_next = true;
+        } else if !arg.starts_with("--") {
+            positional.push(arg.to_string());
+        }
+    }
+    positional
+}
+
+fn exit_with_error(error: &str) -> ! {
+    eprintln!("{}", error);
+    exit(ERROR_EXIT_CODE)
+}
+
 fn execute_login_cmd(rl: &mut Editor<()>) {
     let (email, password) = get_email_password(rl);
+    let weak_password_warning = get_weak_password_warning(&email, &password);
     let response = account_login(email, password);
     print_response(&response);
+    if let Some(warning) = weak_password_warning {
+        print_response(&warning);
+    }
+}
+
+fn get_weak_password_warning(email: &str, password: &str) -> Option<String> {
+    let policy = config::load().ok()?.password_policy;
+    if !policy.warn_on_login {
+        return None;
+    }
+    match strength::check(password, &[email], &policy) {
+        Ok(_) => None,
+        Err(feedback) => Some(format!(
+            "Warning: your account password does not meet this profile's policy.\n{}",
+            feedback
+        )),
+    }
 }
 
 fn account_registration(registration_key: String, email: String, password: String) -> String {
@@ -138,7 +817,10 @@ fn account_registration(registration_key: String, email: String, password: Strin
 fn account_login(email: String, password: String) -> String {
     match account::login(email, password) {
         Ok(()) => "Login success!".to_string(),
-        Err(err) => format!("Login failed: {}", &err),
+        Err(err) => {
+            COMMAND_FAILED.store(true, Ordering::Relaxed);
+            format!("Login failed: {}", err)
+        }
     }
 }
 
@@ -149,6 +831,14 @@ fn account_logout(session_id: &str) -> String {
     }
 }
 
+fn get_export_key() -> Result<Vec<u8>, String> {
+    match util::read_file("export_key.private", true) {
+        Ok(export_key) => Ok(export_key),
+        Err(err) if err.kind() == ErrorKind::NotFound => Err("no_session".to_string()),
+        Err(err) => Err(format!("Could not read export_key: {}", err)),
+    }
+}
+
 fn get_session_file() -> Result<(String, String), String> {
     match util::read_session_file() {
         Ok(response) => Ok(response),
@@ -156,14 +846,66 @@ fn get_session_file() -> Result<(String, String), String> {
     }
 }
 
+// The export key and the session, read under one shared lock so that they come from the same login
+// even if another process is logging in.
+fn get_credentials() -> Result<(Vec<u8>, String, String), String> {
+    util::lock::shared(|| {
+        Ok(get_export_key().and_then(|export_key| {
+            let (session_id, email) = get_session_file()?;
+            Ok((export_key, session_id, email))
+        }))
+    })
+    .map_err(|err| format!("Could not read profile: {}", err))?
+}
+
+// Run a locker operation with the current session. If the session is past the expiry the server
+// gave at login, or the server reports that it expired before the operation changed anything
+// ("unauthorized", see locker.rs), log in again as the same account and run it once more.
+fn with_session<T>(
+    rl: &mut Editor<()>,
+    mut operation: impl FnMut(&str, &[u8], &str) -> Result<T, String>,
+) -> Result<T, String> {
+    let (export_key, session_id, email) = get_credentials()?;
+    let result = match util::state::load().map(|state| state.session) {
+        Ok(Some(session)) if session.is_expired() => Err("unauthorized".to_string()),
+        _ => operation(&email, &export_key, &session_id),
+    };
+    match result {
+        Err(error) if error == "unauthorized" => {
+            print_response(&format!(
+                "Your session has expired. Please enter the password for {} to continue:",
+                email
+            ));
+            let password = get_string("Password", rl, true);
+            account::login(email.to_string(), password)
+                .map_err(|err| format!("Login failed: {}", err))?;
+            let (export_key, session_id, _email) = get_credentials()?;
+            operation(&email, &export_key, &session_id)
+        }
+        result => result,
+    }
+}
+
+fn is_session_error(error: &str) -> bool {
+    error == "unauthorized" || error == "no_session"
+}
+
 fn put_key(
     email: &str,
     key_name: &str,
     export_key: &[u8],
-    secret_message: String,
+    secret: &Secret,
     session_id: &str,
 ) -> Result<String, String> {
-    locker::register_locker(key_name, email, export_key, secret_message, session_id)
+    let secret_message = secret.to_plaintext()?;
+    locker::register_locker(
+        key_name,
+        email,
+        export_key,
+        secret_message,
+        &secret.tags,
+        session_id,
+    )
 }
 
 fn get_key(
@@ -171,8 +913,9 @@ fn get_key(
     key_name: &str,
     export_key: &[u8],
     session_id: &str,
-) -> Result<String, String> {
-    locker::open_locker(key_name, email, export_key, session_id)
+) -> Result<Secret, String> {
+    let plaintext = locker::open_locker(key_name, email, export_key, session_id)?;
+    Secret::from_plaintext(plaintext)
 }
 
 fn delete_key(
@@ -188,7 +931,10 @@ fn print_response(r: &str) {
     println!("{}", r);
 }
 
+// Any error reported here makes a one-shot command exit with ERROR_EXIT_CODE once it finishes;
+// the interactive menu just carries on.
 fn handle_error_response(rl: &mut Editor<()>, error: String) {
+    COMMAND_FAILED.store(true, Ordering::Relaxed);
     if &error == "unauthorized" {
         print_response("Your session may have expired. Please login again:");
         execute_login_cmd(rl);
@@ -206,7 +952,6 @@ fn print_menu() {
 
 fn get_email_password(rl: &mut Editor<()>) -> (String, String) {
     let email = get_email(rl);
-    // TODO password length validation
     let password = get_string("Password", rl, true);
     (email, password)
 }
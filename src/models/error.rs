@@ -20,4 +20,46 @@ pub enum CliError {
 
     #[error("Http Error: {0}")]
     HttpError(String),
+
+    #[error("Ciphertext failed authentication: locker contents were tampered with or swapped")]
+    TamperError,
+
+    #[error("This locker uses the legacy format, which is not bound to its name or account, so the server could have swapped it. Run `keypost rewrap` to upgrade it.")]
+    LegacyCiphertextError,
+
+    #[error("Invalid key: {0}")]
+    InvalidKeyError(String),
+
+    #[error("Malformed Ciphertext Error: {0}")]
+    MalformedCiphertextError(String),
+
+    #[error("Malformed server response: {0}")]
+    MalformedResponseError(String),
+
+    #[error("OPAQUE protocol error: {0:?}")]
+    OpaqueError(#[from] opaque_ke::errors::ProtocolError),
+
+    #[error("Unsupported key stretching suite: {0}")]
+    UnsupportedKsfError(String),
+
+    #[error("The server presented a key with fingerprint {0}, which does not match the pinned key: possible man-in-the-middle attack! If the server's key legitimately changed, verify the fingerprint out of band and run `keypost server-key trust`.")]
+    ServerKeyMismatchError(String),
+
+    #[error("Invalid fingerprint: {0}")]
+    InvalidFingerprintError(String),
+
+    #[error("TLS configuration error: {0}")]
+    TlsConfigError(String),
+
+    #[error("Insecure transport: {0}")]
+    InsecureTransportError(String),
+
+    #[error("Local file error: {0}")]
+    FileError(String),
+
+    #[error("Secure channel message {0} was replayed or out of order (expected {1})")]
+    ReplayError(u64, u64),
+
+    #[error("Rate limited by the server: {0}")]
+    RateLimitedError(String),
 }
@@ -1,6 +1,8 @@
 mod error;
+mod secret;
 
 pub use error::CliError;
+pub use secret::{Secret, SecretType};
 use serde::{Deserialize, Serialize};
 
 // TODO Decide whether to use one request struct to simplify and make code reusable.
@@ -51,6 +53,7 @@ pub struct RegisterStartRequest {
     pub e: String,
     pub i: String,
     pub c: String, // PKCE challenge
+    pub s: String, // key stretching suite the account is registered with
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -65,6 +68,7 @@ pub struct RegisterFinishRequest {
 pub struct LoginStartRequest {
     pub e: String,
     pub i: String,
+    pub v: u32, // highest secure channel version the client supports
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -90,6 +94,17 @@ pub struct RegisterResponse {
 pub struct LoginResponse {
     pub id: u32,
     pub o: String,
+    #[serde(default = "legacy_channel_version")]
+    pub v: u32, // secure channel version chosen by the server
+    #[serde(default = "identity_suite")]
+    pub s: String, // the account's key stretching suite, announced by login/start
+    #[serde(default)]
+    pub x: Option<u64>, // when the new session expires (seconds since the UNIX epoch), from login/verify
+}
+
+// Accounts registered before suites were announced use no key stretching.
+fn identity_suite() -> String {
+    crate::crypto::ksf::IDENTITY_SUITE.to_string()
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -101,14 +116,16 @@ pub struct RegisterLockerResponse {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RegisterLockerStartRequest {
     pub id: String,
-    pub e: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub e: Option<String>, // omitted in privacy mode
     pub i: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RegisterLockerFinishRequest {
     pub id: String,
-    pub e: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub e: Option<String>, // omitted in privacy mode
     pub i: String,
     pub c: String, // locker protocol ciphertext
 }
@@ -116,14 +133,17 @@ pub struct RegisterLockerFinishRequest {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct OpenLockerStartRequest {
     pub id: String,
-    pub e: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub e: Option<String>, // omitted in privacy mode
     pub i: String,
+    pub v: u32, // highest secure channel version the client supports
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct OpenLockerFinishRequest {
     pub id: String,
-    pub e: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub e: Option<String>, // omitted in privacy mode
     pub i: String,
     pub n: u32,
 }
@@ -133,6 +153,13 @@ pub struct OpenLockerResponse {
     pub id: u32,
     pub o: String,
     pub n: u32,
+    #[serde(default = "legacy_channel_version")]
+    pub v: u32, // secure channel version chosen by the server
+}
+
+// Servers that predate channel negotiation don't send a version.
+fn legacy_channel_version() -> u32 {
+    crate::crypto::channel::LEGACY_CHANNEL_VERSION
 }
 
 //TODO Either need to parameterize response structs using generics or all to share the same struct to DRY-up.
@@ -148,6 +175,7 @@ impl OpenLockerResponse {
             id,
             o: "unauthorized".to_string(),
             n,
+            v: legacy_channel_version(),
         }
     }
 
@@ -156,6 +184,7 @@ impl OpenLockerResponse {
             id,
             o: "unknown".to_string(),
             n,
+            v: legacy_channel_version(),
         }
     }
 }
@@ -209,14 +238,16 @@ impl DeleteLockerResponse {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DeleteLockerStartRequest {
     pub id: String,
-    pub e: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub e: Option<String>, // omitted in privacy mode
     pub i: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DeleteLockerFinishRequest {
     pub id: String,
-    pub e: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub e: Option<String>, // omitted in privacy mode
     pub i: String,
     pub n: u32,
 }
@@ -227,3 +258,44 @@ pub struct DeleteLockerResponse {
     pub o: String,
     pub n: u32,
 }
+
+// A statement that the server's static key changed from `old` to `new`, signed by `old` ("previous")
+// or by the operator's offline root key ("root"). Keys and signature are base64.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerKeyRotation {
+    pub old: String,
+    pub new: String,
+    pub issued_at: u64,
+    pub signer: String,
+    pub sig: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ServerKeyRotationsResponse {
+    pub rotations: Vec<ServerKeyRotation>,
+}
+
+// One of the account's sessions. Sessions are named by a handle rather than their id, since the id
+// is what authenticates requests. Times are seconds since the UNIX epoch.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ServerSession {
+    pub h: String, // handle
+    pub created_at: u64,
+    #[serde(default)]
+    pub last_used_at: Option<u64>,
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    #[serde(default)]
+    pub current: bool, // the session making the request
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SessionsResponse {
+    pub sessions: Vec<ServerSession>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RevokeSessionRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub h: Option<String>, // omitted to revoke all of the account's sessions
+}
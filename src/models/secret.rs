@@ -0,0 +1,412 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+// Typed locker contents, serialized as a versioned JSON envelope before encryption:
+//   {"v":1,"type":"login","fields":{"username":"...","password":"..."}}
+// Tags and free-form metadata travel inside the same envelope, so the server never sees them.
+// Lockers written before envelopes existed hold a bare string, which is read back as a note.
+pub const SECRET_ENVELOPE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SecretType {
+    Login,
+    ApiToken,
+    Note,
+    Card,
+    SshKey,
+}
+
+pub struct FieldSpec {
+    pub name: &'static str,
+    pub required: bool,
+    pub secret: bool, // prompted for without echo
+}
+
+const fn field(name: &'static str, required: bool, secret: bool) -> FieldSpec {
+    FieldSpec {
+        name,
+        required,
+        secret,
+    }
+}
+
+const LOGIN_FIELDS: &[FieldSpec] = &[
+    field("username", true, false),
+    field("password", true, true),
+    field("url", false, false),
+    field("notes", false, false),
+];
+const API_TOKEN_FIELDS: &[FieldSpec] = &[
+    field("token", true, true),
+    field("scopes", false, false),
+    field("expiry", false, false),
+    field("notes", false, false),
+];
+const NOTE_FIELDS: &[FieldSpec] = &[field("text", true, true)];
+const CARD_FIELDS: &[FieldSpec] = &[
+    field("number", true, true),
+    field("holder", false, false),
+    field("expiry", true, false),
+    field("cvv", false, true),
+    field("notes", false, false),
+];
+const SSH_KEY_FIELDS: &[FieldSpec] = &[
+    field("private_key", true, true),
+    field("public_key", false, false),
+    field("passphrase", false, true),
+    field("notes", false, false),
+];
+
+impl SecretType {
+    pub fn parse(name: &str) -> Result<SecretType, String> {
+        match name.replace('-', "_").as_str() {
+            "login" => Ok(SecretType::Login),
+            "api_token" => Ok(SecretType::ApiToken),
+            "note" => Ok(SecretType::Note),
+            "card" => Ok(SecretType::Card),
+            "ssh_key" => Ok(SecretType::SshKey),
+            _ => Err(format!(
+                "Unknown secret type '{}' (expected login, api-token, note, card or ssh-key)",
+                name
+            )),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SecretType::Login => "login",
+            SecretType::ApiToken => "api-token",
+            SecretType::Note => "note",
+            SecretType::Card => "card",
+            SecretType::SshKey => "ssh-key",
+        }
+    }
+
+    pub fn fields(&self) -> &'static [FieldSpec] {
+        match self {
+            SecretType::Login => LOGIN_FIELDS,
+            SecretType::ApiToken => API_TOKEN_FIELDS,
+            SecretType::Note => NOTE_FIELDS,
+            SecretType::Card => CARD_FIELDS,
+            SecretType::SshKey => SSH_KEY_FIELDS,
+        }
+    }
+
+    /// The field holding the value that is generated, audited and printed by default.
+    pub fn primary_field(&self) -> &'static str {
+        match self {
+            SecretType::Login => "password",
+            SecretType::ApiToken => "token",
+            SecretType::Note => "text",
+            SecretType::Card => "number",
+            SecretType::SshKey => "private_key",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Secret {
+    pub v: u32,
+    #[serde(rename = "type")]
+    pub kind: SecretType,
+    pub fields: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub meta: BTreeMap<String, String>,
+}
+
+impl Secret {
+    pub fn new(kind: SecretType, fields: BTreeMap<String, String>) -> Result<Secret, String> {
+        let secret = Secret {
+            v: SECRET_ENVELOPE_VERSION,
+            kind,
+            fields: fields
+                .into_iter()
+                .filter(|(_, value)| !value.is_empty())
+                .collect(),
+            tags: Vec::new(),
+            meta: BTreeMap::new(),
+        };
+        secret.validate()?;
+        Ok(secret)
+    }
+
+    pub fn note(text: String) -> Secret {
+        let mut fields = BTreeMap::new();
+        fields.insert("text".to_string(), text);
+        Secret {
+            v: SECRET_ENVELOPE_VERSION,
+            kind: SecretType::Note,
+            fields,
+            tags: Vec::new(),
+            meta: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_tags(mut self, tags: Vec<String>) -> Result<Secret, String> {
+        if let Some(tag) = tags.iter().find(|tag| tag.is_empty() || tag.contains(',')) {
+            return Err(format!("Invalid tag '{}'", tag));
+        }
+        self.tags = tags;
+        self.tags.sort();
+        self.tags.dedup();
+        Ok(self)
+    }
+
+    pub fn with_meta(mut self, meta: BTreeMap<String, String>) -> Secret {
+        self.meta = meta;
+        self
+    }
+
+    /// Parse decrypted locker contents, treating anything that isn't an envelope as a legacy note.
+    pub fn from_plaintext(plaintext: String) -> Result<Secret, String> {
+        match serde_json::from_str::<Secret>(&plaintext) {
+            Ok(secret) if secret.v > SECRET_ENVELOPE_VERSION => Err(format!(
+                "Locker uses secret format v{}, please upgrade keypost-cli",
+                secret.v
+            )),
+            Ok(secret) => Ok(secret),
+            Err(_) => Ok(Secret::note(plaintext)),
+        }
+    }
+
+    pub fn to_plaintext(&self) -> Result<String, String> {
+        serde_json::to_string(self).map_err(|err| format!("Could not serialize secret: {}", err))
+    }
+
+    pub fn field(&self, name: &str) -> Result<&str, String> {
+        self.fields
+            .get(name)
+            .map(|value| value.as_str())
+            .ok_or_else(|| {
+                format!(
+                    "Field '{}' is not set on this {} (available: {})",
+                    name,
+                    self.kind.name(),
+                    self.fields
+                        .keys()
+                        .cloned()
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                )
+            })
+    }
+
+    pub fn primary_value(&self) -> Option<&str> {
+        self.field(self.kind.primary_field()).ok()
+    }
+
+    pub fn display(&self) -> String {
+        let mut lines = match self.kind {
+            SecretType::Note => vec![self.primary_value().unwrap_or_default().to_string()],
+            _ => {
+                let mut lines = vec![format!("type: {}", self.kind.name())];
+                for spec in self.kind.fields() {
+                    if let Some(value) = self.fields.get(spec.name) {
+                        lines.push(format!("{}: {}", spec.name, value));
+                    }
+                }
+                lines
+            }
+        };
+        if !self.tags.is_empty() {
+            lines.push(format!("tags: {}", self.tags.join(", ")));
+        }
+        for (key, value) in &self.meta {
+            lines.push(format!("meta.{}: {}", key, value));
+        }
+        lines.join("\n")
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let specs = self.kind.fields();
+        for name in self.fields.keys() {
+            if !specs.iter().any(|spec| spec.name == name.as_str()) {
+                return Err(format!("Unknown field '{}' for {}", name, self.kind.name()));
+            }
+        }
+        for spec in specs.iter().filter(|spec| spec.required) {
+            if !self.fields.contains_key(spec.name) {
+                return Err(format!(
+                    "Field '{}' is required for {}",
+                    spec.name,
+                    self.kind.name()
+                ));
+            }
+        }
+        if self.kind == SecretType::Card {
+            let number: String = self.field("number")?.split_whitespace().collect();
+            if number.len() < 12 || number.len() > 19 || !number.chars().all(|c| c.is_ascii_digit())
+            {
+                return Err("Card number must be 12-19 digits".to_string());
+            }
+            let expiry = self.field("expiry")?;
+            let valid_expiry = expiry.len() == 5
+                && expiry.as_bytes()[2] == b'/'
+                && matches!(expiry[..2].parse::<u8>(), Ok(1..=12))
+                && expiry[3..].chars().all(|c| c.is_ascii_digit());
+            if !valid_expiry {
+                return Err("Card expiry must be in MM/YY format".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TYPES: [SecretType; 5] = [
+        SecretType::Login,
+        SecretType::ApiToken,
+        SecretType::Note,
+        SecretType::Card,
+        SecretType::SshKey,
+    ];
+
+    fn fields(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn card(number: &str, expiry: &str) -> Result<Secret, String> {
+        Secret::new(
+            SecretType::Card,
+            fields(&[("number", number), ("expiry", expiry)]),
+        )
+    }
+
+    // Every field of the type set, with values that pass the card checks.
+    fn all_fields(kind: SecretType) -> BTreeMap<String, String> {
+        kind.fields()
+            .iter()
+            .map(|spec| {
+                let value = match spec.name {
+                    "number" => "4111 1111 1111 1111",
+                    "expiry" => "09/29",
+                    _ => "value",
+                };
+                (spec.name.to_string(), value.to_string())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn every_type_accepts_all_of_its_fields() {
+        for kind in TYPES.iter() {
+            let secret = Secret::new(*kind, all_fields(*kind)).unwrap();
+            assert!(secret.primary_value().is_some(), "{}", kind.name());
+        }
+    }
+
+    #[test]
+    fn every_required_field_is_enforced() {
+        for kind in TYPES.iter() {
+            for spec in kind.fields().iter().filter(|spec| spec.required) {
+                let mut missing = all_fields(*kind);
+                missing.remove(spec.name);
+                let err = Secret::new(*kind, missing).unwrap_err();
+                assert!(
+                    err.contains(&format!("'{}' is required", spec.name)),
+                    "{}",
+                    err
+                );
+
+                // An empty value counts as missing.
+                let mut empty = all_fields(*kind);
+                empty.insert(spec.name.to_string(), String::new());
+                assert!(Secret::new(*kind, empty).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn optional_fields_may_be_left_out() {
+        let login = Secret::new(
+            SecretType::Login,
+            fields(&[("username", "alice"), ("password", "hunter2"), ("url", "")]),
+        )
+        .unwrap();
+        assert!(login.field("url").is_err());
+    }
+
+    #[test]
+    fn unknown_fields_are_refused() {
+        let err = Secret::new(
+            SecretType::Note,
+            fields(&[("text", "hello"), ("password", "hunter2")]),
+        )
+        .unwrap_err();
+        assert_eq!(err, "Unknown field 'password' for note");
+    }
+
+    #[test]
+    fn card_numbers_must_be_12_to_19_digits() {
+        assert!(card("4111 1111 1111 1111", "09/29").is_ok());
+        assert!(card("411111111111", "09/29").is_ok());
+        assert!(card("4111111111111111111", "09/29").is_ok());
+        for number in [
+            "41111111111",
+            "41111111111111111111",
+            "4111-1111-1111-1111",
+            "４１１１",
+        ]
+        .iter()
+        {
+            assert_eq!(
+                card(number, "09/29").unwrap_err(),
+                "Card number must be 12-19 digits",
+                "{}",
+                number
+            );
+        }
+    }
+
+    #[test]
+    fn card_expiry_must_be_mm_yy() {
+        assert!(card("4111111111111111", "01/30").is_ok());
+        assert!(card("4111111111111111", "12/30").is_ok());
+        for expiry in [
+            "00/30", "13/30", "1/30", "01/2030", "01-30", "01/3x", "é/12",
+        ]
+        .iter()
+        {
+            assert_eq!(
+                card("4111111111111111", expiry).unwrap_err(),
+                "Card expiry must be in MM/YY format",
+                "{}",
+                expiry
+            );
+        }
+    }
+
+    #[test]
+    fn plaintexts_that_are_not_envelopes_read_as_notes() {
+        let secret = Secret::from_plaintext("just a string".to_string()).unwrap();
+        assert_eq!(secret.kind, SecretType::Note);
+        assert_eq!(secret.primary_value(), Some("just a string"));
+
+        let newer = r#"{"v":2,"type":"note","fields":{"text":"x"}}"#;
+        assert!(Secret::from_plaintext(newer.to_string()).is_err());
+    }
+
+    #[test]
+    fn tags_are_sorted_deduplicated_and_checked() {
+        let tags = |tags: &[&str]| tags.iter().map(|tag| tag.to_string()).collect();
+        let secret = Secret::note("x".to_string())
+            .with_tags(tags(&["work", "bank", "work"]))
+            .unwrap();
+        assert_eq!(secret.tags, vec!["bank".to_string(), "work".to_string()]);
+        assert!(Secret::note("x".to_string())
+            .with_tags(tags(&[""]))
+            .is_err());
+        assert!(Secret::note("x".to_string())
+            .with_tags(tags(&["a,b"]))
+            .is_err());
+    }
+}
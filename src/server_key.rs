@@ -0,0 +1,537 @@
+use p256::ecdsa::signature::Verifier;
+use p256::ecdsa::{Signature, VerifyingKey};
+use sha2::{Digest, Sha256};
+use std::convert::TryFrom;
+
+use crate::config;
+use crate::generator;
+use crate::http;
+use crate::models::{CliError, ServerKeyRotation};
+use crate::util;
+use crate::util::state::{self, RotationRecord, ServerKeyState};
+
+// The server's static OPAQUE public key is pinned on first use in the profile state (see
+// util/state.rs). Fingerprints are the SHA-256 of the serialized key, shown as hex or as words from
+// the EFF wordlist, so they can be compared against one published by the server operator.
+//  * `expected` holds a fingerprint obtained out of band before the first login, so that the first
+//    key is checked instead of trusted blindly.
+//  * `pending` holds the last key that didn't match, so it can be inspected and trusted.
+// A key that doesn't match the pin is still accepted if the server can show a chain of rotation
+// statements from the pinned key to it, each signed by the key it replaces or by the offline root
+// key in config. Accepted rotations are appended to `history`. Each must be issued later than the
+// last one accepted, and none may lead back to a key that was pinned before, so that old
+// statements can't be replayed to move the pin back to a retired (possibly compromised) key.
+
+const ROTATION_CONTEXT: &[u8] = b"keypost server key rotation v1";
+const MAX_ROTATION_CHAIN: usize = 16;
+
+const FINGERPRINT_LEN: usize = 32;
+// Word fingerprints cover the first 120 bits: ten words of 12 bits from the first 4096 EFF words.
+const BITS_PER_WORD: usize = 12;
+const FINGERPRINT_WORDS: usize = 10;
+
+pub type Fingerprint = [u8; FINGERPRINT_LEN];
+
+pub fn fingerprint(server_key: &[u8]) -> Fingerprint {
+    Sha256::digest(server_key).into()
+}
+
+pub fn to_hex(fingerprint: &Fingerprint) -> String {
+    fingerprint
+        .chunks(2)
+        .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+        .collect::<Vec<String>>()
+        .join(":")
+}
+
+pub fn to_words(fingerprint: &Fingerprint) -> Result<String, String> {
+    let words = generator::eff_wordlist()?;
+    Ok(word_indexes(fingerprint)
+        .into_iter()
+        .map(|index| words[index])
+        .collect::<Vec<&str>>()
+        .join(" "))
+}
+
+/// Whether `input`, in either hex or word form, matches the fingerprint.
+pub fn matches(fingerprint: &Fingerprint, input: &str) -> Result<bool, String> {
+    Ok(match parse(input)? {
+        ParsedFingerprint::Hex(hex) => hex == to_hex(fingerprint).replace(':', ""),
+        ParsedFingerprint::Words(indexes) => indexes == word_indexes(fingerprint),
+    })
+}
+
+enum ParsedFingerprint {
+    Hex(String), // lowercase, without separators
+    Words(Vec<usize>),
+}
+
+fn parse(input: &str) -> Result<ParsedFingerprint, String> {
+    let hex: String = input
+        .chars()
+        .filter(|c| !matches!(c, ':' | ' ' | '-'))
+        .collect::<String>()
+        .to_lowercase();
+    if hex.len() == FINGERPRINT_LEN * 2 && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Ok(ParsedFingerprint::Hex(hex));
+    }
+    let words = generator::eff_wordlist()?;
+    let indexes = input
+        .split_whitespace()
+        .map(|word| {
+            words[..1 << BITS_PER_WORD]
+                .iter()
+                .position(|candidate| candidate.eq_ignore_ascii_case(word))
+                .ok_or_else(|| format!("'{}' is not a fingerprint word", word))
+        })
+        .collect::<Result<Vec<usize>, String>>()?;
+    if indexes.len() != FINGERPRINT_WORDS {
+        return Err(format!(
+            "Expected a {}-character hex fingerprint or {} words",
+            FINGERPRINT_LEN * 2,
+            FINGERPRINT_WORDS
+        ));
+    }
+    Ok(ParsedFingerprint::Words(indexes))
+}
+
+fn word_indexes(fingerprint: &Fingerprint) -> Vec<usize> {
+    (0..FINGERPRINT_WORDS)
+        .map(|word| {
+            (0..BITS_PER_WORD).fold(0, |index, bit| {
+                let bit = word * BITS_PER_WORD + bit;
+                (index << 1) | ((fingerprint[bit / 8] >> (7 - bit % 8)) & 1) as usize
+            })
+        })
+        .collect()
+}
+
+/// Check the key presented by the server during an OPAQUE exchange against the pin, pinning it if
+/// nothing is pinned yet (and it matches any pre-seeded fingerprint).
+pub fn check_and_pin(server_key: &[u8]) -> Result<(), CliError> {
+    let presented = fingerprint(server_key);
+    match pinned()? {
+        Some(pinned) if pinned == server_key => Ok(()),
+        Some(pinned) => match rotation_chain(&pinned, server_key, &load()?.history) {
+            Ok(Some(records)) => update(|keys| {
+                keys.history.extend(records);
+                keys.pinned = Some(base64::encode(server_key));
+                keys.pending = None;
+            }),
+            Ok(None) => mismatch(server_key, &presented),
+            Err(err) => {
+                update(|keys| keys.pending = Some(base64::encode(server_key)))?;
+                Err(err)
+            }
+        },
+        None => match read_expected()? {
+            Some(expected) if !matches(&presented, &expected).unwrap_or(false) => {
+                mismatch(server_key, &presented)
+            }
+            _ => update(|keys| {
+                keys.pinned = Some(base64::encode(server_key));
+                keys.pending = None;
+                keys.expected = None;
+            }),
+        },
+    }
+}
+
+// The verified rotations leading from the pinned key to the presented one, if the server has them.
+// A configuration error or a failure to fetch the statements is reported.
+fn rotation_chain(
+    pinned: &[u8],
+    presented: &[u8],
+    history: &[RotationRecord],
+) -> Result<Option<Vec<RotationRecord>>, CliError> {
+    let root_key = match config::load()
+        .map_err(CliError::FileError)?
+        .server_key
+        .root_key
+    {
+        Some(root_key) => Some(base64::decode(root_key).map_err(|err| {
+            CliError::FileError(format!("server_key.root_key is not valid base64: {}", err))
+        })?),
+        None => None,
+    };
+    let rotations = http::server_key_rotations()
+        .map_err(|err| {
+            CliError::HttpError(format!(
+                "The server presented a key that does not match the pin, and its key rotations \
+                 could not be fetched: {}",
+                err
+            ))
+        })?
+        .rotations;
+    Ok(walk_rotations(
+        pinned,
+        presented,
+        history,
+        root_key.as_deref(),
+        &rotations,
+    ))
+}
+
+// Follows `rotations` from the pinned key to the presented one. Statements that don't verify, or
+// that are older than or lead back past `history`, are ignored.
+fn walk_rotations(
+    pinned: &[u8],
+    presented: &[u8],
+    history: &[RotationRecord],
+    root_key: Option<&[u8]>,
+    rotations: &[ServerKeyRotation],
+) -> Option<Vec<RotationRecord>> {
+    let mut seen: Vec<String> = history
+        .iter()
+        .flat_map(|record| vec![record.old.to_string(), record.new.to_string()])
+        .collect();
+    seen.push(to_hex(&fingerprint(pinned)));
+    let mut last_issued_at = history.last().map_or(0, |record| record.issued_at);
+    let mut current = pinned.to_vec();
+    let mut records = Vec::new();
+    while current != presented && records.len() < MAX_ROTATION_CHAIN {
+        let next = rotations.iter().find_map(|rotation| {
+            let old = base64::decode(&rotation.old).ok()?;
+            let new = base64::decode(&rotation.new).ok()?;
+            let signer = match rotation.signer.as_str() {
+                "previous" => old.clone(),
+                "root" => root_key?.to_vec(),
+                _ => return None,
+            };
+            match old == current
+                && rotation.issued_at > last_issued_at
+                && !seen.contains(&to_hex(&fingerprint(&new)))
+                && verify_rotation(&signer, rotation, &old, &new)
+            {
+                true => Some((rotation, new)),
+                false => None,
+            }
+        });
+        let (rotation, new) = next?;
+        seen.push(to_hex(&fingerprint(&new)));
+        last_issued_at = rotation.issued_at;
+        records.push(RotationRecord {
+            old: to_hex(&fingerprint(&current)),
+            new: to_hex(&fingerprint(&new)),
+            signer: rotation.signer.to_string(),
+            issued_at: rotation.issued_at,
+            accepted_at: util::now(),
+        });
+        current = new;
+    }
+    match current == presented {
+        true => Some(records),
+        false => None,
+    }
+}
+
+// Signatures are ECDSA P-256 over ROTATION_CONTEXT and the length-prefixed old and new keys and
+// issue time. Only P-256 server keys can sign their own successor; ristretto255 deployments rotate
+// with the root key.
+fn verify_rotation(signer: &[u8], rotation: &ServerKeyRotation, old: &[u8], new: &[u8]) -> bool {
+    let message = rotation_message(old, new, rotation.issued_at);
+    verify_signature(signer, &message, &rotation.sig).is_some()
+}
+
+fn rotation_message(old: &[u8], new: &[u8], issued_at: u64) -> Vec<u8> {
+    let mut message = ROTATION_CONTEXT.to_vec();
+    for key in [old, new].iter() {
+        message.extend_from_slice(&(key.len() as u32).to_be_bytes());
+        message.extend_from_slice(key);
+    }
+    message.extend_from_slice(&issued_at.to_be_bytes());
+    message
+}
+
+fn verify_signature(signer: &[u8], message: &[u8], sig: &str) -> Option<()> {
+    let key = VerifyingKey::from_sec1_bytes(signer).ok()?;
+    let sig_bytes = base64::decode(sig).ok()?;
+    let signature = Signature::try_from(sig_bytes.as_slice()).ok()?;
+    key.verify(message, &signature).ok()
+}
+
+pub fn history() -> Result<Vec<RotationRecord>, CliError> {
+    Ok(load()?.history)
+}
+
+fn mismatch(server_key: &[u8], presented: &Fingerprint) -> Result<(), CliError> {
+    update(|keys| keys.pending = Some(base64::encode(server_key)))?;
+    Err(CliError::ServerKeyMismatchError(to_hex(presented)))
+}
+
+pub fn pin(server_key: &[u8]) -> Result<(), CliError> {
+    update(|keys| {
+        keys.pinned = Some(base64::encode(server_key));
+        keys.pending = None;
+    })
+}
+
+pub fn pinned() -> Result<Option<Vec<u8>>, CliError> {
+    decode_key(load()?.pinned)
+}
+
+pub fn pending() -> Result<Option<Vec<u8>>, CliError> {
+    decode_key(load()?.pending)
+}
+
+pub fn read_expected() -> Result<Option<String>, CliError> {
+    Ok(load()?.expected)
+}
+
+/// Pre-seed the fingerprint the first pinned key must match. It is checked now, rather than at the
+/// next login, so that a typo is reported while it can still be fixed.
+pub fn set_expected(fingerprint: &str) -> Result<(), CliError> {
+    parse(fingerprint).map_err(CliError::InvalidFingerprintError)?;
+    update(|keys| keys.expected = Some(fingerprint.trim().to_string()))
+}
+
+/// Forget the pinned, pending and expected keys; the next login pins on first use again.
+pub fn reset() -> Result<(), CliError> {
+    update(|keys| {
+        keys.pinned = None;
+        keys.pending = None;
+        keys.expected = None;
+    })
+}
+
+fn load() -> Result<ServerKeyState, CliError> {
+    state::load()
+        .map(|state| state.server_key)
+        .map_err(|err| CliError::FileError(format!("Could not read server key state: {}", err)))
+}
+
+fn update(change: impl FnOnce(&mut ServerKeyState)) -> Result<(), CliError> {
+    state::update(|state| change(&mut state.server_key))
+        .map_err(|err| CliError::FileError(format!("Could not write server key state: {}", err)))
+}
+
+// Keys are validated as base64 when the state is loaded.
+fn decode_key(key: Option<String>) -> Result<Option<Vec<u8>>, CliError> {
+    key.map(|key| base64::decode(key).map_err(|err| CliError::FileError(err.to_string())))
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p256::ecdsa::signature::Signer;
+    use p256::ecdsa::SigningKey;
+
+    // Deterministic P-256 keys, standing in for both server keys and the operator's root key.
+    fn signing_key(n: u8) -> SigningKey {
+        SigningKey::from_bytes(&[n; 32]).unwrap()
+    }
+
+    fn public(n: u8) -> Vec<u8> {
+        signing_key(n)
+            .verifying_key()
+            .to_encoded_point(true)
+            .as_bytes()
+            .to_vec()
+    }
+
+    fn rotation(
+        old: u8,
+        new: u8,
+        issued_at: u64,
+        signer: &str,
+        signed_by: u8,
+    ) -> ServerKeyRotation {
+        let signature: Signature =
+            signing_key(signed_by).sign(&rotation_message(&public(old), &public(new), issued_at));
+        ServerKeyRotation {
+            old: base64::encode(public(old)),
+            new: base64::encode(public(new)),
+            issued_at,
+            signer: signer.to_string(),
+            sig: base64::encode(signature),
+        }
+    }
+
+    fn record(old: u8, new: u8, issued_at: u64) -> RotationRecord {
+        RotationRecord {
+            old: to_hex(&fingerprint(&public(old))),
+            new: to_hex(&fingerprint(&public(new))),
+            signer: "previous".to_string(),
+            issued_at,
+            accepted_at: issued_at,
+        }
+    }
+
+    const ROOT: u8 = 100;
+
+    fn walk(
+        pinned: u8,
+        presented: u8,
+        history: &[RotationRecord],
+        rotations: &[ServerKeyRotation],
+    ) -> Option<Vec<RotationRecord>> {
+        walk_rotations(
+            &public(pinned),
+            &public(presented),
+            history,
+            Some(&public(ROOT)),
+            rotations,
+        )
+    }
+
+    #[test]
+    fn follows_a_chain_signed_by_previous_and_root_keys() {
+        // Listed out of order, with an unrelated statement mixed in.
+        let rotations = [
+            rotation(2, 3, 20, "root", ROOT),
+            rotation(7, 8, 5, "previous", 7),
+            rotation(1, 2, 10, "previous", 1),
+        ];
+        let records = walk(1, 3, &[], &rotations).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].old, to_hex(&fingerprint(&public(1))));
+        assert_eq!(records[0].new, to_hex(&fingerprint(&public(2))));
+        assert_eq!(records[0].signer, "previous");
+        assert_eq!(records[1].new, to_hex(&fingerprint(&public(3))));
+        assert_eq!(records[1].signer, "root");
+        assert_eq!(records[1].issued_at, 20);
+    }
+
+    #[test]
+    fn rejects_statements_from_the_wrong_signer() {
+        let cases = [
+            rotation(1, 2, 10, "previous", 2), // signed by the new key
+            rotation(1, 2, 10, "root", 1),     // claims the root key, signed by the old one
+            rotation(1, 2, 10, "operator", ROOT),
+        ];
+        for case in cases.iter() {
+            assert!(walk(1, 2, &[], std::slice::from_ref(case)).is_none());
+        }
+        let root_signed = [rotation(1, 2, 10, "root", ROOT)];
+        assert!(walk_rotations(&public(1), &public(2), &[], None, &root_signed).is_none());
+    }
+
+    #[test]
+    fn rejects_tampered_statements() {
+        let mut later = rotation(1, 2, 10, "previous", 1);
+        later.issued_at = 11;
+        let mut redirected = rotation(1, 2, 10, "previous", 1);
+        redirected.new = base64::encode(public(3));
+        assert!(walk(1, 2, &[], &[later]).is_none());
+        assert!(walk(1, 3, &[], &[redirected]).is_none());
+    }
+
+    #[test]
+    fn rejects_replayed_statements() {
+        // 1 -> 2 was accepted at time 20; a statement issued before that (here one that was signed
+        // but never used) can't be replayed now.
+        let history = [record(1, 2, 20)];
+        assert!(walk(2, 3, &history, &[rotation(2, 3, 15, "previous", 2)]).is_none());
+        assert!(walk(2, 3, &history, &[rotation(2, 3, 20, "previous", 2)]).is_none());
+        assert!(walk(2, 3, &history, &[rotation(2, 3, 21, "previous", 2)]).is_some());
+    }
+
+    #[test]
+    fn rejects_chains_whose_issue_times_do_not_increase() {
+        let same = [
+            rotation(1, 2, 10, "previous", 1),
+            rotation(2, 3, 10, "previous", 2),
+        ];
+        let backwards = [
+            rotation(1, 2, 10, "previous", 1),
+            rotation(2, 3, 9, "previous", 2),
+        ];
+        assert!(walk(1, 3, &[], &same).is_none());
+        assert!(walk(1, 3, &[], &backwards).is_none());
+    }
+
+    #[test]
+    fn rejects_returning_to_a_previously_pinned_key() {
+        let history = [record(1, 2, 10), record(2, 3, 20)];
+        for old_key in [1, 2].iter() {
+            let back = [rotation(3, *old_key, 30, "previous", 3)];
+            assert!(walk(3, *old_key, &history, &back).is_none());
+        }
+        // Nor within one chain.
+        let cycle = [
+            rotation(3, 4, 30, "previous", 3),
+            rotation(4, 3, 40, "previous", 4),
+            rotation(3, 5, 50, "previous", 3),
+        ];
+        assert!(walk(3, 5, &history, &cycle).is_none());
+    }
+
+    #[test]
+    fn limits_the_chain_length() {
+        let chain = |hops: u8| -> Vec<ServerKeyRotation> {
+            (1..=hops)
+                .map(|i| rotation(i, i + 1, i as u64, "previous", i))
+                .collect()
+        };
+        let max = MAX_ROTATION_CHAIN as u8;
+        assert_eq!(walk(1, max + 1, &[], &chain(max)).unwrap().len(), 16);
+        assert!(walk(1, max + 2, &[], &chain(max + 1)).is_none());
+    }
+
+    #[test]
+    fn word_indexes_take_twelve_bits_each() {
+        let mut fp = [0u8; FINGERPRINT_LEN];
+        fp[..3].copy_from_slice(&[0x12, 0x34, 0x56]);
+        let indexes = word_indexes(&fp);
+        assert_eq!(indexes.len(), FINGERPRINT_WORDS);
+        assert_eq!(&indexes[..3], &[0x123, 0x456, 0]);
+        assert!(word_indexes(&[0xff; FINGERPRINT_LEN])
+            .iter()
+            .all(|&index| index == 0xfff));
+    }
+
+    #[test]
+    fn parses_hex_fingerprints_in_any_layout() {
+        let fp = fingerprint(b"server key");
+        let hex = to_hex(&fp);
+        assert_eq!(hex.len(), FINGERPRINT_LEN * 2 + FINGERPRINT_LEN / 2 - 1);
+        let layouts = [
+            hex.clone(),
+            hex.replace(':', ""),
+            hex.replace(':', " ").to_uppercase(),
+            hex.replace(':', "-"),
+        ];
+        for layout in layouts.iter() {
+            assert!(matches!(parse(layout), Ok(ParsedFingerprint::Hex(_))));
+            assert_eq!(matches(&fp, layout), Ok(true), "{}", layout);
+        }
+        assert_eq!(matches(&fingerprint(b"other key"), &hex), Ok(false));
+    }
+
+    #[test]
+    fn parses_word_fingerprints() {
+        let fp = fingerprint(b"server key");
+        let words = to_words(&fp).unwrap();
+        assert_eq!(words.split(' ').count(), FINGERPRINT_WORDS);
+        assert_eq!(matches(&fp, &words), Ok(true));
+        assert_eq!(matches(&fp, &words.to_uppercase()), Ok(true));
+        assert_eq!(matches(&fingerprint(b"other key"), &words), Ok(false));
+        let zero = ["abacus"; FINGERPRINT_WORDS].join(" ");
+        assert_eq!(matches(&[0; FINGERPRINT_LEN], &zero), Ok(true));
+    }
+
+    #[test]
+    fn rejects_malformed_fingerprints() {
+        let fp = fingerprint(b"server key");
+        let hex = to_hex(&fp).replace(':', "");
+        let words = to_words(&fp).unwrap();
+        let first_words = words.split(' ').next().unwrap();
+        let inputs = [
+            "".to_string(),
+            "ab12".to_string(),
+            hex[1..].to_string(),
+            format!("{}0", hex),
+            hex.replace('a', "g"),
+            first_words.to_string(),
+            format!("{} {}", words, first_words),
+            words.replacen(first_words, "notaword", 1),
+            // Words past the first 4096 of the list are never used in fingerprints.
+            words.replacen(first_words, "zoom", 1),
+        ];
+        for input in inputs.iter() {
+            assert!(parse(input).is_err(), "{}", input);
+            assert!(matches(&fp, input).is_err(), "{}", input);
+        }
+    }
+}
@@ -0,0 +1,339 @@
+use crate::config::PasswordPolicyConfig;
+
+// A small zxcvbn-style estimator: the password is split greedily into the cheapest known
+// patterns (common passwords and words, user inputs, sequences, repeats) and whatever is left
+// is costed as brute force. The estimated guesses are then bucketed into a 0-4 score.
+
+const COMMON: &[&str] = &[
+    "password",
+    "123456",
+    "12345678",
+    "qwerty",
+    "abc123",
+    "letmein",
+    "monkey",
+    "dragon",
+    "111111",
+    "baseball",
+    "iloveyou",
+    "trustno1",
+    "sunshine",
+    "master",
+    "welcome",
+    "shadow",
+    "ashley",
+    "football",
+    "jesus",
+    "michael",
+    "ninja",
+    "mustang",
+    "princess",
+    "superman",
+    "starwars",
+    "whatever",
+    "freedom",
+    "batman",
+    "access",
+    "passw0rd",
+    "hello",
+    "charlie",
+    "donald",
+    "admin",
+    "login",
+    "secret",
+    "qwertyuiop",
+    "asdfghjkl",
+    "zxcvbnm",
+    "1qaz2wsx",
+    "qazwsx",
+    "changeme",
+    "default",
+    "keypost",
+    "summer",
+    "winter",
+    "spring",
+    "autumn",
+    "love",
+    "money",
+    "computer",
+    "internet",
+    "soccer",
+    "hockey",
+    "killer",
+    "pepper",
+    "cheese",
+    "banana",
+    "orange",
+    "apple",
+    "flower",
+    "forever",
+    "hunter",
+    "ranger",
+];
+
+// zxcvbn score thresholds expressed as log2(guesses): 10^3, 10^6, 10^8 and 10^10.
+const SCORE_THRESHOLDS: [f64; 4] = [9.97, 19.93, 26.58, 33.22];
+
+#[derive(Debug, PartialEq)]
+enum Pattern {
+    Common,
+    UserInput,
+    Sequence,
+    Repeat,
+}
+
+pub struct Strength {
+    pub score: u8,
+    pub entropy_bits: f64,
+    pub feedback: Vec<String>,
+}
+
+/// Estimate the strength of a password, treating `user_inputs` (e.g. the account email) as
+/// known to an attacker.
+pub fn estimate(password: &str, user_inputs: &[&str]) -> Strength {
+    let chars: Vec<char> = password.chars().collect();
+    let lowered: Vec<char> = chars.iter().map(|c| c.to_ascii_lowercase()).collect();
+    let unleeted: Vec<char> = lowered.iter().map(|c| unleet(*c)).collect();
+    let inputs: Vec<Vec<char>> = user_inputs
+        .iter()
+        .flat_map(|input| input.split(|c: char| !c.is_alphanumeric()))
+        .filter(|part| part.len() >= 3)
+        .map(|part| part.to_lowercase().chars().collect())
+        .collect();
+    let cardinality = cardinality(&chars) as f64;
+
+    let mut patterns: Vec<Pattern> = Vec::new();
+    let mut bits = 0.0;
+    let mut i = 0;
+    while i < chars.len() {
+        let common = COMMON.iter().map(|w| w.chars().collect::<Vec<char>>());
+        if let Some((len, rank)) =
+            longest_match(&lowered[i..], common.clone()).max(longest_match(&unleeted[i..], common))
+        {
+            bits += (rank as f64 + 1.0).log2() + 1.0 + case_bits(&chars[i..i + len]);
+            patterns.push(Pattern::Common);
+            i += len;
+        } else if let Some((len, _)) = longest_match(&unleeted[i..], inputs.iter().cloned()) {
+            bits += 1.0 + case_bits(&chars[i..i + len]);
+            patterns.push(Pattern::UserInput);
+            i += len;
+        } else if let Some(len) = run_length(&chars[i..], |a, b| b as i32 - a as i32 == 1)
+            .or_else(|| run_length(&chars[i..], |a, b| a as i32 - b as i32 == 1))
+        {
+            bits += cardinality.log2() + 1.0;
+            patterns.push(Pattern::Sequence);
+            i += len;
+        } else if let Some(len) = run_length(&chars[i..], |a, b| a == b) {
+            bits += cardinality.log2() + (len as f64).log2();
+            patterns.push(Pattern::Repeat);
+            i += len;
+        } else {
+            bits += cardinality.log2();
+            i += 1;
+        }
+    }
+
+    let score = SCORE_THRESHOLDS.iter().filter(|t| bits >= **t).count() as u8;
+    Strength {
+        score,
+        entropy_bits: bits,
+        feedback: feedback(&chars, &patterns),
+    }
+}
+
+/// Check a password against the profile's policy, returning actionable feedback if it is rejected.
+pub fn check(
+    password: &str,
+    user_inputs: &[&str],
+    policy: &PasswordPolicyConfig,
+) -> Result<Strength, String> {
+    let strength = estimate(password, user_inputs);
+    let mut problems: Vec<String> = Vec::new();
+    if password.chars().count() < policy.min_length {
+        problems.push(format!("Use at least {} characters.", policy.min_length));
+    }
+    if strength.score < policy.min_score {
+        problems.push(format!(
+            "Password is too guessable (score {}/4, {} required).",
+            strength.score, policy.min_score
+        ));
+        problems.extend(strength.feedback.iter().cloned());
+    }
+    match problems.is_empty() {
+        true => Ok(strength),
+        false => Err(format!("Weak password:\n  - {}", problems.join("\n  - "))),
+    }
+}
+
+fn feedback(chars: &[char], patterns: &[Pattern]) -> Vec<String> {
+    let mut feedback: Vec<String> = Vec::new();
+    if patterns.contains(&Pattern::Common) {
+        feedback.push("Avoid common passwords, words and keyboard patterns.".to_string());
+    }
+    if patterns.contains(&Pattern::UserInput) {
+        feedback.push("Avoid using parts of your email address.".to_string());
+    }
+    if patterns.contains(&Pattern::Sequence) {
+        feedback.push("Avoid sequences like \"abc\" or \"123\".".to_string());
+    }
+    if patterns.contains(&Pattern::Repeat) {
+        feedback.push("Avoid repeated characters like \"aaa\".".to_string());
+    }
+    if chars.iter().all(|c| c.is_alphabetic()) || chars.iter().all(|c| c.is_numeric()) {
+        feedback.push("Mix in digits, symbols or uppercase letters.".to_string());
+    }
+    feedback.push(
+        "Add another word or two; uncommon words are better. Try `keypost generate --passphrase`."
+            .to_string(),
+    );
+    feedback
+}
+
+// Returns the length and rank of the longest candidate that prefixes `chars`.
+fn longest_match<I>(chars: &[char], candidates: I) -> Option<(usize, usize)>
+where
+    I: Iterator<Item = Vec<char>>,
+{
+    candidates
+        .enumerate()
+        .filter(|(_, word)| chars.starts_with(word))
+        .map(|(rank, word)| (word.len(), rank))
+        .max_by_key(|(len, _)| *len)
+}
+
+// Returns the length of the run at the start of `chars` if it is at least 3 characters long.
+fn run_length<F>(chars: &[char], continues: F) -> Option<usize>
+where
+    F: Fn(char, char) -> bool,
+{
+    let len = 1 + chars
+        .windows(2)
+        .take_while(|pair| continues(pair[0], pair[1]))
+        .count();
+    match len >= 3 {
+        true => Some(len),
+        false => None,
+    }
+}
+
+fn cardinality(chars: &[char]) -> u32 {
+    let mut cardinality = 0;
+    if chars.iter().any(|c| c.is_ascii_lowercase()) {
+        cardinality += 26;
+    }
+    if chars.iter().any(|c| c.is_ascii_uppercase()) {
+        cardinality += 26;
+    }
+    if chars.iter().any(|c| c.is_ascii_digit()) {
+        cardinality += 10;
+    }
+    if chars.iter().any(|c| c.is_ascii_punctuation() || *c == ' ') {
+        cardinality += 33;
+    }
+    if chars.iter().any(|c| !c.is_ascii()) {
+        cardinality += 100;
+    }
+    cardinality.max(1)
+}
+
+// Capitalising the first letter or the whole word adds about a bit; anything else adds one per
+// uppercase letter.
+fn case_bits(chars: &[char]) -> f64 {
+    let upper = chars.iter().filter(|c| c.is_uppercase()).count();
+    if upper == 0 {
+        0.0
+    } else if upper == chars.len() || (upper == 1 && chars[0].is_uppercase()) {
+        1.0
+    } else {
+        upper as f64
+    }
+}
+
+fn unleet(c: char) -> char {
+    match c {
+        '4' | '@' => 'a',
+        '3' => 'e',
+        '1' | '!' => 'i',
+        '0' => 'o',
+        '$' | '5' => 's',
+        '7' => 't',
+        c => c,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EMAIL: &str = "marguerite.lindqvist@example.com";
+
+    #[test]
+    fn common_passwords_score_zero() {
+        for password in ["password", "123456", "qwerty", "P@ssw0rd", "Password"].iter() {
+            let strength = estimate(password, &[]);
+            assert_eq!(strength.score, 0, "{}", password);
+            assert!(strength
+                .feedback
+                .iter()
+                .any(|line| line.contains("common passwords")));
+        }
+    }
+
+    #[test]
+    fn sequences_and_repeats_are_weak() {
+        let sequence = estimate("abcdefghijkl", &[]);
+        assert!(sequence.score <= 1);
+        assert!(sequence
+            .feedback
+            .iter()
+            .any(|line| line.contains("sequences")));
+        let repeat = estimate("zzzzzzzzzzzz", &[]);
+        assert!(repeat.score <= 1);
+        assert!(repeat.feedback.iter().any(|line| line.contains("repeated")));
+    }
+
+    #[test]
+    fn random_passwords_and_passphrases_score_four() {
+        for password in ["vT7#qLz9!mWx2@Rk", "gravel-otter-quaint-mulch-saffron"].iter() {
+            assert_eq!(estimate(password, &[]).score, 4, "{}", password);
+        }
+    }
+
+    #[test]
+    fn parts_of_user_inputs_cost_almost_nothing() {
+        let password = "MargueriteLindqvist";
+        let alone = estimate(password, &[]);
+        let with_email = estimate(password, &[EMAIL]);
+        assert!(with_email.entropy_bits < alone.entropy_bits / 4.0);
+        assert!(with_email.score < alone.score);
+        assert!(with_email
+            .feedback
+            .iter()
+            .any(|line| line.contains("email address")));
+        // Parts shorter than three characters are not treated as known.
+        assert_eq!(
+            estimate("ab", &["ab@cd.ef"]).entropy_bits,
+            estimate("ab", &[]).entropy_bits
+        );
+    }
+
+    #[test]
+    fn check_applies_the_length_and_score_policy() {
+        let policy = PasswordPolicyConfig::default();
+        assert!(check("vT7#qLz9!mWx2@Rk", &[EMAIL], &policy).is_ok());
+
+        let err = check("vT7#qLz9!", &[], &policy).err().unwrap();
+        assert!(err.contains("Use at least 12 characters."), "{}", err);
+
+        let err = check("password1234", &[], &policy).err().unwrap();
+        assert!(err.contains("too guessable"), "{}", err);
+        assert!(!err.contains("characters."), "{}", err);
+
+        let lenient = PasswordPolicyConfig {
+            min_length: 0,
+            min_score: 0,
+            warn_on_login: false,
+        };
+        assert!(check("password", &[], &lenient).is_ok());
+    }
+}
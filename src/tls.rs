@@ -0,0 +1,260 @@
+use reqwest::blocking::ClientBuilder;
+use reqwest::{redirect, Url};
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::config::TlsConfig;
+use crate::models::CliError;
+
+// Transport security for requests to the server:
+//  * HTTPS is required unless the server is on a loopback address, and redirects away from HTTPS
+//    are refused.
+//  * Certificates are verified against the built-in web PKI roots, or only against the CAs in
+//    `tls.ca_bundle` when it is set.
+//  * With `tls.spki_pins`, the server's certificate must also carry one of the pinned public keys
+//    (base64 SHA-256 of the DER SubjectPublicKeyInfo, as printed by scripts/tls_test_server.sh).
+
+/// The validated base URL of the server, without a trailing slash.
+pub fn server_url(server_url: &str, tls: &TlsConfig) -> Result<String, CliError> {
+    let url = Url::parse(server_url).map_err(|err| {
+        CliError::TlsConfigError(format!("Invalid server_url {}: {}", server_url, err))
+    })?;
+    match url.scheme() {
+        "https" => (),
+        "http" if tls.ca_bundle.is_some() || !tls.spki_pins.is_empty() => {
+            return Err(CliError::InsecureTransportError(format!(
+                "{} uses plain HTTP, but this profile has TLS settings (ca_bundle or spki_pins) for its server. Refusing to downgrade.",
+                server_url
+            )))
+        }
+        "http" if is_loopback(&url) => (),
+        _ => {
+            return Err(CliError::InsecureTransportError(format!(
+                "{} does not use HTTPS, which is required for servers that aren't on this machine.",
+                server_url
+            )))
+        }
+    }
+    Ok(server_url.trim_end_matches('/').to_string())
+}
+
+fn is_loopback(url: &Url) -> bool {
+    match url.host_str() {
+        Some(host) => {
+            host.eq_ignore_ascii_case("localhost")
+                || host
+                    .trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .parse::<IpAddr>()
+                    .is_ok_and(|ip| ip.is_loopback())
+        }
+        None => false,
+    }
+}
+
+// A client builder with these checks installed; http.rs adds timeouts and proxies.
+pub fn client_builder(tls: &TlsConfig) -> Result<ClientBuilder, CliError> {
+    let verifier = PinningVerifier::new(tls)?;
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    Ok(reqwest::blocking::Client::builder()
+        .use_preconfigured_tls(config)
+        .redirect(redirect::Policy::custom(|attempt| {
+            match attempt.url().scheme() {
+                "https" => attempt.follow(),
+                _ => attempt.error("server redirected to a non-HTTPS URL; refusing to downgrade"),
+            }
+        })))
+}
+
+fn root_store(tls: &TlsConfig) -> Result<RootCertStore, CliError> {
+    let mut roots = RootCertStore::empty();
+    match &tls.ca_bundle {
+        Some(path) => {
+            let pem = fs::read(path).map_err(|err| {
+                CliError::TlsConfigError(format!("Could not read {}: {}", path, err))
+            })?;
+            let certs = rustls_pemfile::certs(&mut pem.as_slice()).map_err(|err| {
+                CliError::TlsConfigError(format!("Could not parse {}: {}", path, err))
+            })?;
+            if certs.is_empty() {
+                return Err(CliError::TlsConfigError(format!(
+                    "{} contains no certificates",
+                    path
+                )));
+            }
+            for der in certs {
+                roots.add(&Certificate(der)).map_err(|err| {
+                    CliError::TlsConfigError(format!("Invalid CA certificate in {}: {}", path, err))
+                })?;
+            }
+        }
+        None => roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
+            )
+        })),
+    }
+    Ok(roots)
+}
+
+// Normal web PKI verification, followed by the SPKI pin check when pins are configured.
+struct PinningVerifier {
+    inner: WebPkiVerifier,
+    pins: Vec<Vec<u8>>,
+}
+
+impl PinningVerifier {
+    fn new(tls: &TlsConfig) -> Result<Self, CliError> {
+        let pins = tls
+            .spki_pins
+            .iter()
+            .map(|pin| {
+                base64::decode(pin.trim_start_matches("sha256/"))
+                    .map_err(|_| CliError::TlsConfigError(format!("Invalid SPKI pin {}", pin)))
+            })
+            .collect::<Result<Vec<Vec<u8>>, CliError>>()?;
+        Ok(PinningVerifier {
+            inner: WebPkiVerifier::new(root_store(tls)?, None),
+            pins,
+        })
+    }
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )?;
+        if self.pins.is_empty() {
+            return Ok(verified);
+        }
+        let (_, certificate) =
+            x509_parser::parse_x509_certificate(&end_entity.0).map_err(|err| {
+                rustls::Error::General(format!("could not parse the server certificate: {}", err))
+            })?;
+        let hash = Sha256::digest(certificate.public_key().raw);
+        match self.pins.iter().any(|pin| pin.as_slice() == &hash[..]) {
+            true => Ok(verified),
+            false => Err(rustls::Error::General(format!(
+                "server certificate public key sha256/{} does not match any pinned key",
+                base64::encode(hash)
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use std::path::PathBuf;
+
+    use rcgen::{BasicConstraints, CertificateParams, IsCa};
+
+    // A CA and a leaf certificate for localhost that it signed.
+    struct TestChain {
+        ca_bundle: PathBuf,
+        leaf: Certificate,
+        leaf_pin: String,
+    }
+
+    fn test_chain(name: &str) -> TestChain {
+        let mut ca_params = CertificateParams::new(Vec::new());
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = rcgen::Certificate::from_params(ca_params).unwrap();
+        let leaf =
+            rcgen::Certificate::from_params(CertificateParams::new(vec!["localhost".to_string()]))
+                .unwrap();
+        let ca_bundle = std::env::temp_dir().join(format!(
+            "keypost-tls-test-{}-{}.pem",
+            std::process::id(),
+            name
+        ));
+        fs::write(&ca_bundle, ca.serialize_pem().unwrap()).unwrap();
+        TestChain {
+            ca_bundle,
+            leaf: Certificate(leaf.serialize_der_with_signer(&ca).unwrap()),
+            leaf_pin: format!(
+                "sha256/{}",
+                base64::encode(Sha256::digest(&leaf.get_key_pair().public_key_der()))
+            ),
+        }
+    }
+
+    fn verify(
+        chain: &TestChain,
+        spki_pins: Vec<String>,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let tls = TlsConfig {
+            ca_bundle: Some(chain.ca_bundle.to_string_lossy().into_owned()),
+            spki_pins,
+        };
+        PinningVerifier::new(&tls).unwrap().verify_server_cert(
+            &chain.leaf,
+            &[],
+            &ServerName::try_from("localhost").unwrap(),
+            &mut std::iter::empty(),
+            &[],
+            SystemTime::now(),
+        )
+    }
+
+    #[test]
+    fn accepts_a_pinned_key() {
+        let chain = test_chain("match");
+        let result = verify(&chain, vec![chain.leaf_pin.clone()]);
+        fs::remove_file(&chain.ca_bundle).unwrap();
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn refuses_a_key_that_is_not_pinned() {
+        let chain = test_chain("mismatch");
+        let other_pin = format!("sha256/{}", base64::encode([0u8; 32]));
+        let mismatched = verify(&chain, vec![other_pin]);
+        // The same chain passes without pins, so the refusal above is the pin check's.
+        let unpinned = verify(&chain, Vec::new());
+        fs::remove_file(&chain.ca_bundle).unwrap();
+        assert!(
+            matches!(mismatched, Err(rustls::Error::General(err)) if err.contains(&chain.leaf_pin))
+        );
+        assert!(unpinned.is_ok());
+    }
+
+    #[test]
+    fn refuses_plain_http_with_tls_settings() {
+        let chain = test_chain("downgrade");
+        let tls = TlsConfig {
+            ca_bundle: Some(chain.ca_bundle.to_string_lossy().into_owned()),
+            spki_pins: vec![chain.leaf_pin.clone()],
+        };
+        let http = server_url("http://localhost:8000", &tls);
+        let https = server_url("https://localhost:8000/", &tls);
+        fs::remove_file(&chain.ca_bundle).unwrap();
+        assert!(matches!(http, Err(CliError::InsecureTransportError(_))));
+        assert_eq!(https.unwrap(), "https://localhost:8000");
+    }
+}
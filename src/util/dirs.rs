@@ -0,0 +1,177 @@
+use std::env;
+use std::fs::{self, DirBuilder, Metadata, Permissions};
+use std::io::{Error, ErrorKind};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use super::state::STATE_FILE;
+
+// Where the profile lives, resolved at runtime (in this order):
+//  * the --state-dir flag, or KEYPOST_HOME: config.json and state both go in that directory;
+//  * an existing ~/.keypost-cli, so profiles from older clients keep working;
+//  * if XDG_CONFIG_HOME or XDG_STATE_HOME is set, config.json goes in $XDG_CONFIG_HOME/keypost and
+//    state in $XDG_STATE_HOME/keypost (each defaulting as in the XDG base directory spec);
+//  * otherwise ~/.keypost-cli.
+// Directories are created with mode 0700, and refused if they belong to another user or are
+// accessible by anyone else. Older clients created ~/.keypost-cli with the default mode (usually
+// 0755), so the first time this client uses one, it tightens it to 0700 instead.
+const APP_DIR: &str = "keypost";
+const LEGACY_DIR: &str = ".keypost-cli";
+
+struct Dirs {
+    config: PathBuf,
+    state: PathBuf,
+    legacy: bool,
+}
+
+static DIRS: OnceLock<Dirs> = OnceLock::new();
+
+/// Resolve, create and check the profile directories. Called once at startup.
+pub fn init(state_dir: Option<&str>) -> Result<(), Error> {
+    let dirs = resolve(state_dir)?;
+    if dirs.legacy {
+        tighten_legacy_dir(&dirs.state)?;
+    }
+    ensure_private_dir(&dirs.config)?;
+    ensure_private_dir(&dirs.state)?;
+    DIRS.set(dirs)
+        .map_err(|_| Error::other("State directory already initialized"))
+}
+
+pub fn config_path(file_name: &str) -> Result<PathBuf, Error> {
+    Ok(dirs()?.config.join(file_name))
+}
+
+pub fn state_path(file_name: &str) -> Result<PathBuf, Error> {
+    Ok(dirs()?.state.join(file_name))
+}
+
+pub fn state_dir() -> Result<&'static Path, Error> {
+    Ok(&dirs()?.state)
+}
+
+fn dirs() -> Result<&'static Dirs, Error> {
+    DIRS.get()
+        .ok_or_else(|| Error::other("State directory not initialized"))
+}
+
+fn resolve(state_dir: Option<&str>) -> Result<Dirs, Error> {
+    let explicit = state_dir
+        .map(PathBuf::from)
+        .or_else(|| env_path("KEYPOST_HOME"));
+    if let Some(dir) = explicit {
+        return Ok(Dirs {
+            config: dir.clone(),
+            state: dir,
+            legacy: false,
+        });
+    }
+    let home = env_path("HOME").ok_or_else(|| {
+        Error::new(
+            ErrorKind::NotFound,
+            "HOME is not set; set KEYPOST_HOME or pass --state-dir",
+        )
+    })?;
+    let legacy = home.join(LEGACY_DIR);
+    let xdg_config = env_path("XDG_CONFIG_HOME");
+    let xdg_state = env_path("XDG_STATE_HOME");
+    if legacy.exists() || (xdg_config.is_none() && xdg_state.is_none()) {
+        return Ok(Dirs {
+            config: legacy.clone(),
+            state: legacy,
+            legacy: true,
+        });
+    }
+    Ok(Dirs {
+        config: xdg_config
+            .unwrap_or_else(|| home.join(".config"))
+            .join(APP_DIR),
+        state: xdg_state
+            .unwrap_or_else(|| home.join(".local/state"))
+            .join(APP_DIR),
+        legacy: false,
+    })
+}
+
+// Unset and empty variables are ignored, and so are relative XDG paths, as the spec requires.
+fn env_path(name: &str) -> Option<PathBuf> {
+    let path = PathBuf::from(env::var_os(name).filter(|value| !value.is_empty())?);
+    match name.starts_with("XDG_") && path.is_relative() {
+        true => None,
+        false => Some(path),
+    }
+}
+
+fn ensure_private_dir(dir: &Path) -> Result<(), Error> {
+    match fs::metadata(dir) {
+        Ok(metadata) => check_private_dir(dir, &metadata),
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            DirBuilder::new().recursive(true).mode(0o700).create(dir)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+// A ~/.keypost-cli without state.json (which every run writes) was only used by older clients,
+// which didn't restrict it.
+// Once this client has used it, a directory that is accessible by others again is refused as usual.
+fn tighten_legacy_dir(dir: &Path) -> Result<(), Error> {
+    let metadata = match fs::symlink_metadata(dir) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    if !metadata.is_dir()
+        || metadata.uid() != current_uid()
+        || metadata.mode() & 0o077 == 0
+        || dir.join(STATE_FILE).exists()
+    {
+        return Ok(());
+    }
+    fs::set_permissions(dir, Permissions::from_mode(0o700))?;
+    eprintln!(
+        "Restricted {} to mode 700 (it was {:o}).",
+        dir.display(),
+        metadata.mode() & 0o777
+    );
+    Ok(())
+}
+
+fn check_private_dir(dir: &Path, metadata: &Metadata) -> Result<(), Error> {
+    if !metadata.is_dir() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} is not a directory", dir.display()),
+        ));
+    }
+    check_private(dir, metadata)
+}
+
+/// Refuse a file or directory that belongs to another user or that anyone else can access.
+pub fn check_private(path: &Path, metadata: &Metadata) -> Result<(), Error> {
+    if metadata.uid() != current_uid() {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("{} belongs to another user", path.display()),
+        ));
+    }
+    if metadata.mode() & 0o077 != 0 {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "{} is accessible by other users (mode {:o}); run `chmod {} {}` to use it",
+                path.display(),
+                metadata.mode() & 0o777,
+                if metadata.is_dir() { "700" } else { "600" },
+                path.display()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn current_uid() -> u32 {
+    // geteuid has no preconditions and cannot fail.
+    unsafe { libc::geteuid() }
+}
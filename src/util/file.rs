@@ -1,73 +1,421 @@
-use std::env;
-use std::fs;
-use std::io::Error;
-use std::os::unix::fs::PermissionsExt;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-const SESSION_ID_LEN: usize = 20;
+use super::dirs::{self, state_path};
+use super::{lock, state};
 
-fn default_dir() -> String {
-    String::from(env!("HOME")) + "/.keypost-cli"
-}
+const LOCKER_INDEX_FILE: &str = "lockers.private";
 
-fn delete_file(file_name: &str) -> Result<(), Error> {
-    let file_path = default_dir() + "/" + file_name;
-    fs::remove_file(file_path)
+pub fn delete_file(file_name: &str) -> Result<(), Error> {
+    lock::exclusive(|| fs::remove_file(state_path(file_name)?))
 }
 
-pub fn create_default_directory() -> Result<(), Error> {
-    let dir = default_dir();
-    match fs::read_dir(&dir) {
-        Ok(_) => Ok(()),
-        Err(err) => {
-            println!("DEBUG: Looked for directory {}. But error {:?}", &dir, err);
-            println!("INFO: Will attempt to create directory {}", &dir);
-            fs::create_dir(&dir)
-        }
+pub fn write_to_secure_file(file_name: &str, bytes: &[u8], base64: bool) -> Result<(), Error> {
+    match base64 {
+        true => write_atomically(file_name, base64::encode(bytes).as_bytes()),
+        false => write_atomically(file_name, bytes),
     }
 }
 
-pub fn write_to_secure_file(file_name: &str, bytes: &[u8], base64: bool) -> Result<(), Error> {
-    let file_path = default_dir() + "/" + file_name;
-    match base64 {
-        true => fs::write(&file_path, base64::encode(bytes))?,
-        false => fs::write(&file_path, bytes)?,
+// Files are created with O_EXCL and mode 0600 under a fresh temporary name in the same directory,
+// written with a checksum line ahead of their contents, synced and then renamed over the target.
+// Key material is never readable by anyone else, and readers see either the old or the new
+// contents, never a partial write.
+pub fn write_atomically(file_name: &str, bytes: &[u8]) -> Result<(), Error> {
+    lock::exclusive(|| write_file_atomically(file_name, bytes))
+}
+
+fn write_file_atomically(file_name: &str, bytes: &[u8]) -> Result<(), Error> {
+    let file_path = state_path(file_name)?;
+    let temp_path = state_path(&format!(".{}.{:016x}.tmp", file_name, OsRng.next_u64()))?;
+    let result = write_new_file(&temp_path, &with_checksum(bytes))
+        .and_then(|()| fs::rename(&temp_path, &file_path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result?;
+    // Make the rename itself durable.
+    match file_path.parent() {
+        Some(dir) => File::open(dir)?.sync_all(),
+        None => Ok(()),
     }
-    let mut p = fs::metadata(&file_path)?.permissions();
-    p.set_mode(0o600);
-    fs::set_permissions(&file_path, p)
+}
+
+fn write_new_file(path: &Path, bytes: &[u8]) -> Result<(), Error> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(bytes)?;
+    file.sync_all()
 }
 
 pub fn read_file(file_name: &str, base64: bool) -> Result<Vec<u8>, Error> {
     match base64 {
         true => match read_base64_file_path(file_name) {
-            Ok(s) => Ok(base64::decode(s).expect("Could not base64 decode bytes!")),
+            Ok(s) => base64::decode(s).map_err(|err| Error::new(ErrorKind::InvalidData, err)),
             Err(err) => Err(err),
         },
-        false => {
-            let file_path = default_dir() + "/" + file_name;
-            fs::read(file_path)
-        }
+        false => read_checked(file_name),
     }
 }
 
 pub fn read_base64_file_path(file_name: &str) -> Result<String, Error> {
-    let file_path = default_dir() + "/" + file_name;
-    fs::read_to_string(file_path)
+    String::from_utf8(read_checked(file_name)?)
+        .map_err(|err| Error::new(ErrorKind::InvalidData, err))
+}
+
+// A file is only trusted if it is a regular file (not a symlink) that belongs to this user and
+// can't be accessed by anyone else, and its checksum matches. Files written before checksums were
+// added have none; they are read as they are (unless empty) and given one at startup.
+fn read_checked(file_name: &str) -> Result<Vec<u8>, Error> {
+    lock::shared(|| read_checked_file(file_name))
 }
 
-pub fn write_session_file(session_id: &[u8], email: &str) -> Result<(), Error> {
-    let session = [session_id, email.as_bytes()].concat();
-    write_to_secure_file("session_id.public", &session, true)
+fn read_checked_file(file_name: &str) -> Result<Vec<u8>, Error> {
+    let path = state_path(file_name)?;
+    let mut file = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(&path)
+        .map_err(|err| match err.raw_os_error() {
+            Some(libc::ELOOP) => Error::new(
+                ErrorKind::InvalidData,
+                format!("{} is a symbolic link", path.display()),
+            ),
+            _ => err,
+        })?;
+    let metadata = file.metadata()?;
+    if !metadata.is_file() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("{} is not a regular file", path.display()),
+        ));
+    }
+    dirs::check_private(&path, &metadata)?;
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+    verify_checksum(&path, bytes)
 }
 
+const CHECKSUM_PREFIX: &[u8] = b"keypost-sha256:";
+
+fn with_checksum(bytes: &[u8]) -> Vec<u8> {
+    [CHECKSUM_PREFIX, checksum(bytes).as_bytes(), b"\n", bytes].concat()
+}
+
+fn verify_checksum(path: &Path, bytes: Vec<u8>) -> Result<Vec<u8>, Error> {
+    let corrupted = || {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("{} is corrupted (checksum mismatch)", path.display()),
+        )
+    };
+    // No file is ever written empty, so an empty file (or one cut off inside the checksum line)
+    // was truncated.
+    if CHECKSUM_PREFIX.starts_with(&bytes) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("{} is truncated", path.display()),
+        ));
+    }
+    if !bytes.starts_with(CHECKSUM_PREFIX) {
+        return Ok(bytes);
+    }
+    let newline = bytes
+        .iter()
+        .position(|byte| *byte == b'\n')
+        .ok_or_else(corrupted)?;
+    let contents = &bytes[newline + 1..];
+    match &bytes[CHECKSUM_PREFIX.len()..newline] == checksum(contents).as_bytes() {
+        true => Ok(contents.to_vec()),
+        false => Err(corrupted()),
+    }
+}
+
+/// Give a checksum to the files written by clients from before checksums existed, so that later
+/// damage to them is detected too. Run once at startup, after the state has been migrated.
+pub fn add_missing_checksums() -> Result<(), Error> {
+    if lock::shared(files_without_checksum)?.is_empty() {
+        return Ok(());
+    }
+    lock::exclusive(|| {
+        for file_name in files_without_checksum()? {
+            // A file that can't be trusted is left as it is, to be reported when it is used.
+            if let Ok(bytes) = read_checked(&file_name) {
+                write_atomically(&file_name, &bytes)?;
+            }
+        }
+        Ok(())
+    })
+}
+
+// The files written through this module are state.json and the *.private files.
+fn files_without_checksum() -> Result<Vec<String>, Error> {
+    let mut file_names = Vec::new();
+    for entry in fs::read_dir(dirs::state_dir()?)? {
+        let file_name = match entry?.file_name().into_string() {
+            Ok(file_name) => file_name,
+            Err(_) => continue,
+        };
+        if file_name.starts_with('.')
+            || !(file_name.ends_with(".private") || file_name == state::STATE_FILE)
+        {
+            continue;
+        }
+        let mut header = Vec::new();
+        File::open(state_path(&file_name)?)?
+            .take(CHECKSUM_PREFIX.len() as u64)
+            .read_to_end(&mut header)?;
+        // Skip truncated files, which can't be given a checksum.
+        if !CHECKSUM_PREFIX.starts_with(&header) {
+            file_names.push(file_name);
+        }
+    }
+    Ok(file_names)
+}
+
+fn checksum(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// The current session's id (base64) and email, from the profile state.
 pub fn read_session_file() -> Result<(String, String), Error> {
-    let session = base64::decode(read_base64_file_path("session_id.public")?)
-        .expect("Could not base64 decode session file!");
-    let (session_id, email_bytes) = session.split_at(SESSION_ID_LEN);
-    let email = String::from_utf8(email_bytes.to_vec()).expect("Could not parse into String");
-    Ok((base64::encode(session_id).to_string(), email.to_string()))
+    match state::load()?.session {
+        Some(session) => Ok((session.id, session.email)),
+        None => Err(Error::new(ErrorKind::NotFound, "No session")),
+    }
+}
+
+// Only forgets the session if it is still `session_id`, so a logout racing a login in another
+// process doesn't remove the session that login just created.
+pub fn delete_session_file(session_id: &str) -> Result<(), Error> {
+    state::update(|state| {
+        if state
+            .session
+            .as_ref()
+            .is_some_and(|session| session.id == session_id)
+        {
+            state.session = None;
+        }
+    })
+}
+
+// The secure channel version negotiated at login, which later locker exchanges must not go below.
+pub fn read_channel_version() -> Result<u32, Error> {
+    Ok(state::load()?
+        .session
+        .map_or(1, |session| session.channel_version))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LockerIndexEntry {
+    pub id: String,
+    pub updated_at: u64, // seconds since the UNIX epoch
+    #[serde(default)]
+    pub tags: Vec<String>, // copy of the tags inside the encrypted locker, for filtering
+    #[serde(default)]
+    pub blinded: bool, // stored on the server under its blinded id rather than its plaintext name
+}
+
+impl LockerIndexEntry {
+    pub fn in_folder(&self, folder: &str) -> bool {
+        let folder = folder.trim_end_matches('/');
+        folder.is_empty() || self.id.starts_with(&format!("{}/", folder))
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag)
+    }
+}
+
+// Older clients kept the index as a plain list of names. Their entries read as never updated,
+// untagged and not yet blinded, and are rewritten in the current format by the next change to the
+// index.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredIndexEntry {
+    Entry(LockerIndexEntry),
+    Name(String),
+}
+
+impl From<StoredIndexEntry> for LockerIndexEntry {
+    fn from(entry: StoredIndexEntry) -> Self {
+        match entry {
+            StoredIndexEntry::Entry(entry) => entry,
+            StoredIndexEntry::Name(id) => LockerIndexEntry {
+                id,
+                updated_at: 0,
+                tags: Vec::new(),
+                blinded: false,
+            },
+        }
+    }
+}
+
+// The server has no way to list lockers, so the names of lockers created from this client are
+// kept locally.
+pub fn read_locker_index() -> Result<Vec<LockerIndexEntry>, Error> {
+    match read_file(LOCKER_INDEX_FILE, false) {
+        Ok(bytes) => serde_json::from_slice::<Vec<StoredIndexEntry>>(&bytes)
+            .map(|entries| entries.into_iter().map(LockerIndexEntry::from).collect())
+            .map_err(|err| Error::new(ErrorKind::InvalidData, err.to_string())),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(err),
+    }
+}
+
+pub fn add_to_locker_index(locker_id: &str, tags: &[String], updated_at: u64) -> Result<(), Error> {
+    lock::exclusive(|| {
+        let mut lockers = read_locker_index()?;
+        lockers.retain(|entry| entry.id != locker_id);
+        lockers.push(LockerIndexEntry {
+            id: locker_id.to_string(),
+            updated_at,
+            tags: tags.to_vec(),
+            blinded: true,
+        });
+        lockers.sort_by(|a, b| a.id.cmp(&b.id));
+        write_locker_index(&lockers)
+    })
+}
+
+// Record that a locker already sits under its blinded id, e.g. one moved by an earlier client.
+pub fn mark_blinded_in_locker_index(locker_id: &str) -> Result<(), Error> {
+    lock::exclusive(|| {
+        let mut lockers = read_locker_index()?;
+        for entry in lockers.iter_mut().filter(|entry| entry.id == locker_id) {
+            entry.blinded = true;
+        }
+        write_locker_index(&lockers)
+    })
+}
+
+pub fn remove_from_locker_index(locker_id: &str) -> Result<(), Error> {
+    lock::exclusive(|| {
+        let mut lockers = read_locker_index()?;
+        lockers.retain(|entry| entry.id != locker_id);
+        write_locker_index(&lockers)
+    })
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
 }
 
-pub fn delete_session_file() -> Result<(), Error> {
-    delete_file("session_id.public")
+fn write_locker_index(lockers: &[LockerIndexEntry]) -> Result<(), Error> {
+    let bytes = serde_json::to_vec(lockers)
+        .map_err(|err| Error::new(ErrorKind::InvalidData, err.to_string()))?;
+    write_to_secure_file(LOCKER_INDEX_FILE, &bytes, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_index(json: &str) -> Vec<LockerIndexEntry> {
+        serde_json::from_str::<Vec<StoredIndexEntry>>(json)
+            .unwrap()
+            .into_iter()
+            .map(LockerIndexEntry::from)
+            .collect()
+    }
+
+    #[test]
+    fn index_entries_from_before_blinding_are_not_blinded() {
+        let index = parse_index(r#"["plain", {"id": "dated", "updated_at": 5, "tags": ["a"]}]"#);
+        assert_eq!(index[0].id, "plain");
+        assert_eq!(index[0].updated_at, 0);
+        assert!(!index[0].blinded);
+        assert_eq!(index[1].tags, vec!["a".to_string()]);
+        assert!(!index[1].blinded);
+    }
+
+    #[test]
+    fn index_entries_keep_their_blinded_flag() {
+        let entry = LockerIndexEntry {
+            id: "moved".to_string(),
+            updated_at: 7,
+            tags: Vec::new(),
+            blinded: true,
+        };
+        let json = serde_json::to_string(&vec![entry]).unwrap();
+        assert!(parse_index(&json)[0].blinded);
+    }
+
+    fn verify(bytes: &[u8]) -> Result<Vec<u8>, String> {
+        verify_checksum(Path::new("test.private"), bytes.to_vec()).map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn checksummed_files_read_back_their_contents() {
+        for contents in [&b"{}"[..], b"line one\nline two\n", b"\n"].iter() {
+            assert_eq!(verify(&with_checksum(contents)).unwrap(), contents.to_vec());
+        }
+    }
+
+    #[test]
+    fn truncated_files_are_refused() {
+        let written = with_checksum(b"{\"version\": 1}");
+        let newline = written.iter().position(|byte| *byte == b'\n').unwrap();
+        assert_eq!(verify(b"").unwrap_err(), "test.private is truncated");
+        assert_eq!(
+            verify(b"keypost-sha").unwrap_err(),
+            "test.private is truncated"
+        );
+        for len in [
+            CHECKSUM_PREFIX.len() + 10,
+            newline,
+            newline + 1,
+            written.len() - 1,
+        ]
+        .iter()
+        {
+            assert_eq!(
+                verify(&written[..*len]).unwrap_err(),
+                "test.private is corrupted (checksum mismatch)",
+                "{}",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn mismatched_checksums_are_refused() {
+        let mut contents_changed = with_checksum(b"secret");
+        *contents_changed.last_mut().unwrap() ^= 1;
+        let mut checksum_changed = with_checksum(b"secret");
+        checksum_changed[CHECKSUM_PREFIX.len()] ^= 1;
+        let other_file = [
+            &with_checksum(b"other")[..CHECKSUM_PREFIX.len() + 65],
+            b"secret",
+        ]
+        .concat();
+        for bytes in [contents_changed, checksum_changed, other_file].iter() {
+            assert_eq!(
+                verify(bytes).unwrap_err(),
+                "test.private is corrupted (checksum mismatch)"
+            );
+        }
+    }
+
+    #[test]
+    fn files_without_a_checksum_line_are_read_as_they_are() {
+        assert_eq!(verify(b"c2VjcmV0").unwrap(), b"c2VjcmV0".to_vec());
+        assert_eq!(verify(b"keypost").unwrap_err(), "test.private is truncated");
+    }
 }
@@ -0,0 +1,127 @@
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::dirs::{self, state_path};
+use crate::config;
+
+// Advisory locks on the state directory, so that CLI processes running in parallel (from scripts,
+// say) never interleave their changes. Reads take a shared lock and changes an exclusive one, with
+// flock(2) on a lock file next to the state. Waiters give up after `lock_timeout_secs`.
+//
+// Locks are held per process and nest: a change that reads the state while holding the exclusive
+// lock doesn't wait for itself. A shared lock can't be upgraded, since two processes upgrading at
+// once would wait for each other; take the exclusive lock for the whole read-modify-write instead.
+const LOCK_FILE: &str = ".lock";
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    Shared,
+    Exclusive,
+}
+
+struct Held {
+    _file: File, // closing it releases the lock
+    mode: Mode,
+    depth: usize,
+}
+
+thread_local! {
+    static HELD: RefCell<Option<Held>> = const { RefCell::new(None) };
+}
+
+/// Run `read` holding (at least) a shared lock on the state directory.
+pub fn shared<T>(read: impl FnOnce() -> Result<T, Error>) -> Result<T, Error> {
+    with_lock(Mode::Shared, read)
+}
+
+/// Run `change` holding the exclusive lock on the state directory.
+pub fn exclusive<T>(change: impl FnOnce() -> Result<T, Error>) -> Result<T, Error> {
+    with_lock(Mode::Exclusive, change)
+}
+
+fn with_lock<T>(mode: Mode, f: impl FnOnce() -> Result<T, Error>) -> Result<T, Error> {
+    let nested = HELD.with(|held| match held.borrow_mut().as_mut() {
+        Some(held) if held.mode == Mode::Exclusive || mode == Mode::Shared => {
+            held.depth += 1;
+            Ok(true)
+        }
+        Some(_) => Err(Error::other(
+            "Cannot upgrade a shared lock on the state directory",
+        )),
+        None => Ok(false),
+    })?;
+    if !nested {
+        let file = acquire(mode)?;
+        HELD.with(|held| {
+            *held.borrow_mut() = Some(Held {
+                _file: file,
+                mode,
+                depth: 1,
+            })
+        });
+    }
+    let result = f();
+    HELD.with(|held| {
+        let mut held = held.borrow_mut();
+        if let Some(lock) = held.as_mut() {
+            lock.depth -= 1;
+            if lock.depth == 0 {
+                *held = None;
+            }
+        }
+    });
+    result
+}
+
+fn acquire(mode: Mode) -> Result<File, Error> {
+    let path = state_path(LOCK_FILE)?;
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .mode(0o600)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(&path)?;
+    dirs::check_private(&path, &file.metadata()?)?;
+    let operation = match mode {
+        Mode::Shared => libc::LOCK_SH,
+        Mode::Exclusive => libc::LOCK_EX,
+    };
+    let timeout_secs = config::load()
+        .map_err(|err| Error::new(ErrorKind::InvalidData, err))?
+        .lock_timeout_secs;
+    let deadline = Instant::now() + Duration::from_secs(timeout_secs);
+    loop {
+        // flock has no preconditions beyond a valid descriptor, which `file` owns.
+        if unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) } == 0 {
+            return Ok(file);
+        }
+        let err = Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::EWOULDBLOCK) if Instant::now() < deadline => thread::sleep(POLL_INTERVAL),
+            Some(libc::EWOULDBLOCK) => {
+                return Err(Error::new(
+                    ErrorKind::TimedOut,
+                    format!(
+                        "Timed out after {}s waiting for {} lock on {}; another keypost-cli \
+                         process is using this profile",
+                        timeout_secs,
+                        match mode {
+                            Mode::Shared => "a shared",
+                            Mode::Exclusive => "the exclusive",
+                        },
+                        path.display()
+                    ),
+                ))
+            }
+            Some(libc::EINTR) => continue,
+            _ => return Err(err),
+        }
+    }
+}
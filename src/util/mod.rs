@@ -1,3 +1,6 @@
+pub mod dirs;
 pub mod file;
+pub mod lock;
+pub mod state;
 
 pub use file::*;
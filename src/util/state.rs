@@ -0,0 +1,238 @@
+use serde::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind};
+
+use super::{file, lock};
+
+// A profile's session, server key pin and suites, kept in one versioned document. It is validated
+// whenever it is read and replaced atomically whenever it changes. Keys (export_key.private,
+// session_key.private) and the locker index stay in their own files.
+//
+// Profiles from before the document existed kept their session and pinned server key in loose
+// files (LEGACY_FILES); they are migrated once at startup (see migrate), and then removed.
+pub const STATE_FILE: &str = "state.json";
+pub const STATE_VERSION: u32 = 1;
+
+const LEGACY_SESSION_FILE: &str = "session_id.public";
+const LEGACY_SESSION_ID_LEN: usize = 20;
+const LEGACY_PINNED_FILE: &str = "server.public";
+const LEGACY_FILES: [&str; 2] = [LEGACY_SESSION_FILE, LEGACY_PINNED_FILE];
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct State {
+    pub version: u32,
+    #[serde(default)]
+    pub session: Option<Session>,
+    #[serde(default)]
+    pub server_key: ServerKeyState,
+    #[serde(default)]
+    pub ksf_suite: Option<String>, // the suite the server announced at the last login
+    #[serde(default)]
+    pub cipher_suite: Option<String>, // the OPAQUE group the account was registered with
+}
+
+// Times are seconds since the UNIX epoch.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Session {
+    pub id: String, // base64; authenticates requests
+    pub email: String,
+    pub channel_version: u32, // negotiated at login; locker exchanges must not go below it
+    #[serde(default)]
+    pub logged_in_at: Option<u64>, // unknown for sessions from before it was recorded
+    #[serde(default)]
+    pub expires_at: Option<u64>, // if the server reported one
+}
+
+impl Session {
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= file::now())
+    }
+}
+
+// See server_key.rs. Keys are base64.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ServerKeyState {
+    #[serde(default)]
+    pub pinned: Option<String>,
+    #[serde(default)]
+    pub pending: Option<String>, // the last key that didn't match the pin
+    #[serde(default)]
+    pub expected: Option<String>, // fingerprint the first pinned key must match
+    #[serde(default)]
+    pub history: Vec<RotationRecord>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RotationRecord {
+    pub old: String, // hex fingerprints
+    pub new: String,
+    pub signer: String,
+    pub issued_at: u64,
+    pub accepted_at: u64,
+}
+
+/// Create the state document, from the loose files of older clients if there are any. Run once at
+/// startup, before anything takes a shared lock: migrating needs the exclusive lock, which can't
+/// be taken while a shared one is held.
+pub fn migrate() -> Result<(), Error> {
+    // Most runs find the document in place, and shouldn't wait for other readers to find that.
+    if lock::shared(read)?.is_some() {
+        return Ok(());
+    }
+    lock::exclusive(|| match read()? {
+        Some(_) => Ok(()),
+        None => migrate_legacy_files().map(|_| ()),
+    })
+}
+
+// A missing document (one deleted since startup) reads as an empty profile.
+pub fn load() -> Result<State, Error> {
+    Ok(lock::shared(read)?.unwrap_or_else(|| State {
+        version: STATE_VERSION,
+        ..State::default()
+    }))
+}
+
+/// Load the state, apply `change` and write it back if it is still valid. Other processes wait
+/// until the change is written.
+pub fn update<T>(change: impl FnOnce(&mut State) -> T) -> Result<T, Error> {
+    lock::exclusive(|| {
+        let mut state = load()?;
+        let result = change(&mut state);
+        validate(&state)?;
+        save(&state)?;
+        Ok(result)
+    })
+}
+
+fn read() -> Result<Option<State>, Error> {
+    match file::read_file(STATE_FILE, false) {
+        Ok(bytes) => {
+            let state = parse(&bytes)?;
+            validate(&state)?;
+            Ok(Some(state))
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+fn save(state: &State) -> Result<(), Error> {
+    let bytes = serde_json::to_vec_pretty(state)
+        .map_err(|err| invalid(format!("Could not serialize {}: {}", STATE_FILE, err)))?;
+    file::write_atomically(STATE_FILE, &bytes)
+}
+
+// Documents written by older clients are upgraded here one version at a time, as the format
+// changes. A newer document is refused rather than read with fields missing.
+fn parse(bytes: &[u8]) -> Result<State, Error> {
+    let state: State = serde_json::from_slice(bytes)
+        .map_err(|err| invalid(format!("Could not parse {}: {}", STATE_FILE, err)))?;
+    match state.version {
+        STATE_VERSION => Ok(state),
+        version if version > STATE_VERSION => Err(invalid(format!(
+            "{} has version {}, but this keypost-cli only understands version {}. Upgrade \
+             keypost-cli to use this profile.",
+            STATE_FILE, version, STATE_VERSION
+        ))),
+        version => Err(invalid(format!(
+            "{} has unknown version {}",
+            STATE_FILE, version
+        ))),
+    }
+}
+
+fn validate(state: &State) -> Result<(), Error> {
+    if let Some(session) = &state.session {
+        if base64::decode(&session.id).map_or(true, |id| id.is_empty()) {
+            return Err(invalid("session id is not valid base64".to_string()));
+        }
+        if session.email.is_empty() {
+            return Err(invalid("session has no email".to_string()));
+        }
+        if session.channel_version == 0 {
+            return Err(invalid("session has no channel version".to_string()));
+        }
+    }
+    let keys = &state.server_key;
+    for (name, key) in [("pinned", &keys.pinned), ("pending", &keys.pending)].iter() {
+        if let Some(key) = key {
+            if base64::decode(key).map_or(true, |key| key.is_empty()) {
+                return Err(invalid(format!("{} server key is not valid base64", name)));
+            }
+        }
+    }
+    for (name, suite) in [("ksf", &state.ksf_suite), ("cipher", &state.cipher_suite)].iter() {
+        if suite.as_deref().is_some_and(str::is_empty) {
+            return Err(invalid(format!("{} suite is empty", name)));
+        }
+    }
+    Ok(())
+}
+
+fn invalid(message: String) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("Invalid profile state: {}", message),
+    )
+}
+
+// Build the state from the loose files of older clients, save it, and only then remove them.
+fn migrate_legacy_files() -> Result<State, Error> {
+    let state = State {
+        version: STATE_VERSION,
+        session: legacy_session()?,
+        server_key: ServerKeyState {
+            pinned: read_legacy_text(LEGACY_PINNED_FILE)?,
+            ..ServerKeyState::default()
+        },
+        ..State::default()
+    };
+    validate(&state)?;
+    save(&state)?;
+    for file_name in LEGACY_FILES.iter() {
+        if let Err(err) = file::delete_file(file_name) {
+            if err.kind() != ErrorKind::NotFound {
+                return Err(err);
+            }
+        }
+    }
+    Ok(state)
+}
+
+// The old session file held the 20-byte session id followed by the email, base64 encoded. Older
+// clients only spoke the first channel version and didn't record when the session started. A
+// malformed file is dropped, which only means logging in again.
+fn legacy_session() -> Result<Option<Session>, Error> {
+    let bytes = match file::read_file(LEGACY_SESSION_FILE, true) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) if err.kind() == ErrorKind::InvalidData => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    if bytes.len() <= LEGACY_SESSION_ID_LEN {
+        return Ok(None);
+    }
+    let (id, email) = bytes.split_at(LEGACY_SESSION_ID_LEN);
+    let email = match String::from_utf8(email.to_vec()) {
+        Ok(email) => email,
+        Err(_) => return Ok(None),
+    };
+    Ok(Some(Session {
+        id: base64::encode(id),
+        email,
+        channel_version: crate::crypto::channel::LEGACY_CHANNEL_VERSION,
+        logged_in_at: None,
+        expires_at: None,
+    }))
+}
+
+fn read_legacy_text(file_name: &str) -> Result<Option<String>, Error> {
+    match file::read_file(file_name, false) {
+        Ok(bytes) => String::from_utf8(bytes)
+            .map(|text| Some(text.trim().to_string()))
+            .map_err(|_| invalid(format!("Could not parse {}", file_name))),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
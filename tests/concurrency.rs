@@ -0,0 +1,15 @@
+use std::process::Command;
+
+// The scenarios live in the script, which also runs on its own; see scripts/test_concurrency.sh.
+#[test]
+fn parallel_processes_share_a_profile_safely() {
+    let status = Command::new("sh")
+        .arg(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/scripts/test_concurrency.sh"
+        ))
+        .env("KEYPOST_BIN", env!("CARGO_BIN_EXE_keypost-cli"))
+        .status()
+        .expect("Could not run scripts/test_concurrency.sh");
+    assert!(status.success(), "scripts/test_concurrency.sh failed");
+}